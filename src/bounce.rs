@@ -0,0 +1,258 @@
+//! Delivery status notifications (RFC 3464) for spooled messages that can't be delivered.
+//!
+//! Once a spooled message was acknowledged to the client,
+//! filtermail is responsible for telling the sender if it fails (RFC 5321, section 6.1).
+
+use crate::esmtp::{Ret, decode_xtext};
+use crate::reply::SmtpReply;
+use crate::smtp_server::Envelope;
+use crate::trace::{format_date, header_fields};
+use std::time::SystemTime;
+
+/// Builds the notification to the sender about the recipients the message failed for.
+///
+/// Recipients are reported if their outcome is an error
+/// and they did not opt out with `NOTIFY` (RFC 3461, section 4.1).
+/// Returns `None` if there is nothing to report or the message is a notification itself,
+/// as it has the null reverse-path then.
+pub fn bounce(
+    envelope: &Envelope,
+    outcomes: &[Result<(), SmtpReply>],
+    hostname: &str,
+    id: &str,
+    time: SystemTime,
+) -> Option<Envelope> {
+    if envelope.mail_from.is_empty() {
+        return None;
+    }
+
+    let failed: Vec<(usize, &SmtpReply)> = outcomes
+        .iter()
+        .enumerate()
+        .filter_map(|(index, outcome)| outcome.as_ref().err().map(|reply| (index, reply)))
+        .filter(|&(index, _)| {
+            let notify = envelope
+                .rcpt_parameters
+                .get(index)
+                .and_then(|parameters| parameters.notify);
+            notify.is_none_or(|notify| notify.failure)
+        })
+        .collect();
+    if failed.is_empty() {
+        return None;
+    }
+
+    let boundary = format!("{id}/{hostname}");
+    let mut data = format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{hostname}>\r\n\
+         To: <{}>\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Date: {}\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n\
+         \tboundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n\
+         This is the mail system at host {hostname}.\r\n\
+         \r\n\
+         Your message could not be delivered to the following recipients:\r\n\
+         \r\n",
+        envelope.mail_from,
+        format_date(time)
+    );
+    for &(index, reply) in &failed {
+        let recipient = envelope.rcpt_to.get(index).map_or("", String::as_str);
+        data.push_str(&format!("<{recipient}>: {}\r\n", diagnostic(reply)));
+    }
+
+    data.push_str(&format!(
+        "\r\n--{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n\
+         Reporting-MTA: dns; {hostname}\r\n"
+    ));
+    let envid = envelope
+        .mail_parameters
+        .envid
+        .as_deref()
+        .and_then(decode_xtext);
+    if let Some(envid) = envid {
+        data.push_str(&format!("Original-Envelope-Id: {envid}\r\n"));
+    }
+    for &(index, reply) in &failed {
+        data.push_str("\r\n");
+        // ORCPT is xtext, the DSN has the decoded address (RFC 3461, section 6.3).
+        let orcpt = envelope
+            .rcpt_parameters
+            .get(index)
+            .and_then(|parameters| parameters.orcpt.as_deref())
+            .and_then(decode_xtext);
+        if let Some(orcpt) = orcpt {
+            data.push_str(&format!("Original-Recipient: {orcpt}\r\n"));
+        }
+        let recipient = envelope.rcpt_to.get(index).map_or("", String::as_str);
+        let status = reply.enhanced.map_or_else(
+            || format!("{}.0.0", reply.code / 100),
+            |code| code.to_string(),
+        );
+        data.push_str(&format!(
+            "Final-Recipient: rfc822; {recipient}\r\n\
+             Action: failed\r\n\
+             Status: {status}\r\n\
+             Diagnostic-Code: smtp; {}\r\n",
+            diagnostic(reply)
+        ));
+    }
+
+    let mut data = data.into_bytes();
+    data.extend_from_slice(format!("\r\n--{boundary}\r\n").as_bytes());
+    match envelope.mail_parameters.ret {
+        Some(Ret::Headers) => {
+            let (fields, _) = header_fields(&envelope.data);
+            data.extend_from_slice(b"Content-Type: text/rfc822-headers\r\n\r\n");
+            data.extend_from_slice(&fields.concat());
+        }
+        Some(Ret::Full) | None => {
+            data.extend_from_slice(b"Content-Type: message/rfc822\r\n");
+            if !envelope.data.is_ascii() {
+                data.extend_from_slice(b"Content-Transfer-Encoding: 8bit\r\n");
+            }
+            data.extend_from_slice(b"\r\n");
+            data.extend_from_slice(&envelope.data);
+        }
+    }
+    if !data.ends_with(b"\r\n") {
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    Some(Envelope {
+        mail_from: String::new(),
+        rcpt_to: vec![envelope.mail_from.clone()],
+        rcpt_parameters: vec![Default::default()],
+        data,
+        ..Default::default()
+    })
+}
+
+/// The reply on a single line.
+fn diagnostic(reply: &SmtpReply) -> String {
+    let mut diagnostic = reply.code.to_string();
+    if let Some(enhanced) = reply.enhanced {
+        diagnostic.push_str(&format!(" {enhanced}"));
+    }
+    for line in &reply.lines {
+        diagnostic.push_str(&format!(" {line}"));
+    }
+    diagnostic
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::esmtp::{MailParameters, Notify, RcptParameters};
+    use mailparse::{MailHeaderMap, parse_mail};
+    use std::time::UNIX_EPOCH;
+
+    fn envelope() -> Envelope {
+        Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec![
+                "bob@example.net".to_string(),
+                "carol@example.net".to_string(),
+                "dave@example.net".to_string(),
+            ],
+            mail_parameters: MailParameters {
+                envid: Some("QQ+2B314159".to_string()),
+                ..Default::default()
+            },
+            rcpt_parameters: vec![
+                RcptParameters {
+                    orcpt: Some("rfc822;Bob+2Btag+3Dx@example.net".to_string()),
+                    ..Default::default()
+                },
+                RcptParameters {
+                    notify: Some(Notify::default()),
+                    ..Default::default()
+                },
+                RcptParameters::default(),
+            ],
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_bounce() {
+        let outcomes = [
+            Err(SmtpReply::new(550, (5, 1, 1), "User unknown")),
+            Err(SmtpReply::new(550, (5, 1, 1), "User unknown")),
+            Ok(()),
+        ];
+        let bounce = bounce(&envelope(), &outcomes, "example.org", "1.2.3", UNIX_EPOCH).unwrap();
+        assert_eq!(bounce.mail_from, "");
+        assert_eq!(bounce.rcpt_to, ["alice@example.org"]);
+
+        let message = parse_mail(&bounce.data).unwrap();
+        assert_eq!(
+            message.ctype.params.get("report-type").map(String::as_str),
+            Some("delivery-status")
+        );
+        assert_eq!(
+            message.headers.get_first_value("Auto-Submitted").as_deref(),
+            Some("auto-replied")
+        );
+        assert_eq!(message.subparts.len(), 3);
+
+        // Carol asked not to be notified, Dave got the message.
+        let text = message.subparts[0].get_body().unwrap();
+        assert!(text.contains("<bob@example.net>: 550 5.1.1 User unknown"));
+        assert!(!text.contains("carol") && !text.contains("dave"));
+
+        let status = String::from_utf8(message.subparts[1].get_body_raw().unwrap()).unwrap();
+        assert!(status.starts_with(
+            "Reporting-MTA: dns; example.org\r\n\
+             Original-Envelope-Id: QQ+314159\r\n\
+             \r\n\
+             Original-Recipient: rfc822;Bob+tag=x@example.net\r\n\
+             Final-Recipient: rfc822; bob@example.net\r\n\
+             Action: failed\r\n\
+             Status: 5.1.1\r\n\
+             Diagnostic-Code: smtp; 550 5.1.1 User unknown\r\n"
+        ));
+
+        let returned = message.subparts[2].get_body_raw().unwrap();
+        assert!(returned.starts_with(b"Subject: test\r\n\r\nbody\r\n"));
+    }
+
+    #[test]
+    fn test_bounce_headers_only() {
+        let mut envelope = envelope();
+        envelope.mail_parameters.ret = Some(Ret::Headers);
+        let outcomes = [Err(SmtpReply::new(451, (4, 4, 1), "Connection refused"))];
+        let bounce = bounce(&envelope, &outcomes, "example.org", "1.2.3", UNIX_EPOCH).unwrap();
+
+        let message = parse_mail(&bounce.data).unwrap();
+        let returned = &message.subparts[2];
+        assert_eq!(returned.ctype.mimetype, "text/rfc822-headers");
+        assert_eq!(returned.get_body_raw().unwrap(), b"Subject: test\r\n");
+        let status = String::from_utf8(message.subparts[1].get_body_raw().unwrap()).unwrap();
+        assert!(status.contains("Status: 4.4.1\r\n"));
+    }
+
+    #[test]
+    fn test_no_bounce() {
+        let mut envelope = envelope();
+        let outcomes = [Ok(()), Err(SmtpReply::new(550, (5, 1, 1), "User unknown"))];
+        // Carol opted out with NOTIFY=NEVER.
+        assert!(bounce(&envelope, &outcomes, "example.org", "1.2.3", UNIX_EPOCH).is_none());
+
+        // Notifications are never sent about notifications.
+        envelope.mail_from = String::new();
+        let outcomes = [Err(SmtpReply::new(550, (5, 1, 1), "User unknown"))];
+        assert!(bounce(&envelope, &outcomes, "example.org", "1.2.3", UNIX_EPOCH).is_none());
+    }
+}
@@ -1,8 +1,16 @@
 //! Configuration file handling for filtermail.
 
+use crate::listener::ListenAddr;
+use crate::reinject::{Endpoint, LmtpReinjector, Reinjector, SendmailReinjector, SmtpReinjector};
+use crate::smtp_server::{Limits, Protocol, Timeouts};
+use crate::spool::{Spool, SpoolingReinjector};
+use crate::trace::Trace;
+use crate::xclient::AuthorizedHosts;
 use serde::{Deserialize, Deserializer};
-use std::num::NonZeroU32;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// Chatmail configuration subset used by filtermail.
 #[derive(Debug, Clone, Deserialize)]
@@ -11,16 +19,66 @@ pub struct Config {
     pub filtermail_smtp_port: u16,
     #[serde(default = "Config::default_filtermail_smtp_port_incoming")]
     pub filtermail_smtp_port_incoming: u16,
+    #[serde(default = "Config::default_filtermail_listen_host")]
+    pub filtermail_listen_host: IpAddr,
+    pub filtermail_smtp_socket: Option<PathBuf>,
+    pub filtermail_smtp_socket_incoming: Option<PathBuf>,
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub filtermail_socket_mode: Option<u32>,
+    pub filtermail_socket_owner: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_hosts")]
+    pub filtermail_xclient_hosts: AuthorizedHosts,
+    #[serde(default)]
+    pub filtermail_lmtp: bool,
+    #[serde(default)]
+    pub filtermail_lmtp_incoming: bool,
+    #[serde(default)]
+    pub filtermail_trace_headers: bool,
+    #[serde(default)]
+    pub filtermail_trace_headers_incoming: bool,
     #[serde(default = "Config::default_postfix_reinject_port")]
     pub postfix_reinject_port: u16,
     #[serde(default = "Config::default_postfix_reinject_port_incoming")]
     pub postfix_reinject_port_incoming: u16,
+    #[serde(default = "Config::default_postfix_reinject_host")]
+    pub postfix_reinject_host: String,
+    #[serde(default = "Config::default_postfix_reinject_host")]
+    pub postfix_reinject_host_incoming: String,
+    pub postfix_reinject_socket: Option<PathBuf>,
+    pub postfix_reinject_socket_incoming: Option<PathBuf>,
+    #[serde(default)]
+    pub postfix_reinject_lmtp: bool,
+    #[serde(default)]
+    pub postfix_reinject_lmtp_incoming: bool,
+    pub postfix_reinject_sendmail: Option<PathBuf>,
+    pub postfix_reinject_sendmail_incoming: Option<PathBuf>,
+    #[serde(default = "Config::default_postfix_reinject_pool_size")]
+    pub postfix_reinject_pool_size: usize,
+    pub filtermail_spool_dir: Option<PathBuf>,
     #[serde(default = "Config::default_max_message_size")]
     pub max_message_size: usize,
     #[serde(default = "Config::default_max_user_send_per_minute")]
     pub max_user_send_per_minute: NonZeroU32,
     #[serde(default = "Config::default_max_user_send_burst_size")]
     pub max_user_send_burst_size: NonZeroU32,
+    #[serde(default = "Config::default_smtp_greeting_timeout")]
+    pub smtp_greeting_timeout: u64,
+    #[serde(default = "Config::default_smtp_mail_timeout")]
+    pub smtp_mail_timeout: u64,
+    #[serde(default = "Config::default_smtp_rcpt_timeout")]
+    pub smtp_rcpt_timeout: u64,
+    #[serde(default = "Config::default_smtp_data_init_timeout")]
+    pub smtp_data_init_timeout: u64,
+    #[serde(default = "Config::default_smtp_data_block_timeout")]
+    pub smtp_data_block_timeout: u64,
+    #[serde(default = "Config::default_smtp_data_done_timeout")]
+    pub smtp_data_done_timeout: u64,
+    #[serde(default = "Config::default_smtp_shutdown_timeout")]
+    pub smtp_shutdown_timeout: u64,
+    #[serde(default = "Config::default_smtp_max_sessions")]
+    pub smtp_max_sessions: NonZeroUsize,
+    #[serde(default = "Config::default_smtp_max_transactions")]
+    pub smtp_max_transactions: NonZeroUsize,
     #[serde(default, deserialize_with = "deserialize_sequence")]
     pub passthrough_senders: Vec<String>,
     #[serde(default, deserialize_with = "deserialize_sequence")]
@@ -29,6 +87,45 @@ pub struct Config {
     mailboxes_dir: Option<PathBuf>,
 }
 
+/// Filter role served by a listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Mail received from other servers.
+    Incoming,
+    /// Mail sent by local users.
+    Outgoing,
+}
+
+impl Role {
+    /// All roles, served together in the combined mode.
+    pub const ALL: [Role; 2] = [Role::Incoming, Role::Outgoing];
+
+    /// Name used on the command line and for systemd sockets.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Role::Incoming => "incoming",
+            Role::Outgoing => "outgoing",
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.name() == s)
+            .ok_or_else(|| format!("Unknown role: {s}"))
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct ConfigWrapper {
     // The whole actual config is under `params` section.
@@ -51,11 +148,36 @@ where
     })
 }
 
+/// Custom deserializer to parse octal permission bits such as `0660`.
+fn deserialize_mode<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: Option<String> = Deserialize::deserialize(deserializer)?;
+    s.map(|mode| u32::from_str_radix(mode.trim(), 8).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Custom deserializer to parse the peers allowed to send `XCLIENT` and `XFORWARD`.
+fn deserialize_hosts<'de, D>(deserializer: D) -> Result<AuthorizedHosts, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: Option<String> = Deserialize::deserialize(deserializer)?;
+    s.unwrap_or_default()
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
 impl Config {
     /// Load configuration from a file.
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self, crate::error::Error> {
-        let content = std::fs::read_to_string(path)?;
-        let wrapped_config: ConfigWrapper = serini::from_str(&content)?;
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse configuration from the content of a file.
+    pub fn parse(content: &str) -> Result<Self, crate::error::Error> {
+        let wrapped_config: ConfigWrapper = serini::from_str(content)?;
         Ok(wrapped_config.params)
     }
 
@@ -67,14 +189,137 @@ impl Config {
         }
     }
 
+    /// Address to listen on for the role, a Unix socket if configured.
+    pub fn listen_addr(&self, role: Role) -> ListenAddr {
+        let (socket, port) = match role {
+            Role::Incoming => (
+                &self.filtermail_smtp_socket_incoming,
+                self.filtermail_smtp_port_incoming,
+            ),
+            Role::Outgoing => (&self.filtermail_smtp_socket, self.filtermail_smtp_port),
+        };
+
+        match socket {
+            Some(path) => ListenAddr::Unix {
+                path: path.clone(),
+                mode: self.filtermail_socket_mode,
+                owner: self.filtermail_socket_owner.clone(),
+            },
+            None => ListenAddr::Tcp(SocketAddr::new(self.filtermail_listen_host, port)),
+        }
+    }
+
+    /// Protocol spoken to Postfix for the role, LMTP if enabled.
+    pub fn protocol(&self, role: Role) -> Protocol {
+        let lmtp = match role {
+            Role::Incoming => self.filtermail_lmtp_incoming,
+            Role::Outgoing => self.filtermail_lmtp,
+        };
+        match lmtp {
+            true => Protocol::Lmtp,
+            false => Protocol::Smtp,
+        }
+    }
+
+    /// Trace headers added to the mail reinjected for the role, if enabled.
+    pub fn trace(&self, role: Role) -> Option<Trace> {
+        let enabled = match role {
+            Role::Incoming => self.filtermail_trace_headers_incoming,
+            Role::Outgoing => self.filtermail_trace_headers,
+        };
+        enabled.then(|| Trace::new(&self.mail_domain, role, self.protocol(role)))
+    }
+
+    /// Reinjects the mail accepted for the role,
+    /// spooling it if the target fails temporarily and a spool is configured.
+    pub fn reinjector(&self, role: Role) -> Box<dyn Reinjector> {
+        let target = self.reinject_target(role);
+        match self.spool(role) {
+            Some(spool) => Box::new(SpoolingReinjector::new(target, spool)),
+            None => target,
+        }
+    }
+
+    /// Spool of the role, a subdirectory of the spool directory if there is one.
+    pub fn spool(&self, role: Role) -> Option<Spool> {
+        let dir = self.filtermail_spool_dir.as_ref()?;
+        Some(Spool::new(dir.join(role.name()), &self.mail_domain))
+    }
+
+    /// Backend delivering the mail accepted for the role to the reinjection target.
+    ///
+    /// A `sendmail` command takes precedence over a Unix socket,
+    /// which takes precedence over the host and port.
+    pub fn reinject_target(&self, role: Role) -> Box<dyn Reinjector> {
+        let (host, port, socket, lmtp, sendmail) = match role {
+            Role::Incoming => (
+                &self.postfix_reinject_host_incoming,
+                self.postfix_reinject_port_incoming,
+                &self.postfix_reinject_socket_incoming,
+                self.postfix_reinject_lmtp_incoming,
+                &self.postfix_reinject_sendmail_incoming,
+            ),
+            Role::Outgoing => (
+                &self.postfix_reinject_host,
+                self.postfix_reinject_port,
+                &self.postfix_reinject_socket,
+                self.postfix_reinject_lmtp,
+                &self.postfix_reinject_sendmail,
+            ),
+        };
+
+        if let Some(command) = sendmail {
+            return Box::new(SendmailReinjector::new(command.clone()));
+        }
+        let endpoint = match socket {
+            Some(path) => Endpoint::Unix(path.clone()),
+            None => Endpoint::Tcp {
+                host: host.clone(),
+                port,
+            },
+        };
+        let pool_size = self.postfix_reinject_pool_size;
+        match lmtp {
+            true => Box::new(LmtpReinjector::new(endpoint).pooled(pool_size)),
+            false => Box::new(SmtpReinjector::new(endpoint).pooled(pool_size)),
+        }
+    }
+
+    /// SMTP session timeouts, configured in seconds.
+    pub fn smtp_timeouts(&self) -> Timeouts {
+        Timeouts {
+            greeting: Duration::from_secs(self.smtp_greeting_timeout),
+            mail: Duration::from_secs(self.smtp_mail_timeout),
+            rcpt: Duration::from_secs(self.smtp_rcpt_timeout),
+            data_init: Duration::from_secs(self.smtp_data_init_timeout),
+            data_block: Duration::from_secs(self.smtp_data_block_timeout),
+            data_done: Duration::from_secs(self.smtp_data_done_timeout),
+            shutdown: Duration::from_secs(self.smtp_shutdown_timeout),
+        }
+    }
+
+    /// Limits on concurrent SMTP sessions and message processing.
+    pub fn smtp_limits(&self) -> Limits {
+        Limits {
+            max_sessions: self.smtp_max_sessions.get(),
+            max_transactions: self.smtp_max_transactions.get(),
+        }
+    }
+
     /// Check if not encrypted mail is allowed for the given address.
+    ///
+    /// The bare `Postmaster` recipient (RFC 5321, section 4.5.1) is the postmaster of `mail_domain`.
     pub fn is_cleartext_ok(&self, addr: &str) -> bool {
+        if addr.eq_ignore_ascii_case("postmaster") {
+            return self.is_cleartext_ok(&format!("postmaster@{}", self.mail_domain));
+        }
         if addr.is_empty() || !addr.contains('@') || addr.contains('/') {
             return false;
         }
 
+        // Mailbox directories are named after the lowercase address.
         let mut enforce_e2ee = self.mailboxes_dir();
-        enforce_e2ee.push(addr);
+        enforce_e2ee.push(addr.to_lowercase());
         enforce_e2ee.push("enforceE2EEincoming");
 
         !enforce_e2ee.exists()
@@ -88,15 +333,51 @@ impl Config {
     const fn default_filtermail_smtp_port_incoming() -> u16 {
         10081
     }
+    const fn default_filtermail_listen_host() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    }
     const fn default_postfix_reinject_port() -> u16 {
         10025
     }
     const fn default_postfix_reinject_port_incoming() -> u16 {
         10026
     }
+    fn default_postfix_reinject_host() -> String {
+        "localhost".to_string()
+    }
     const fn default_max_message_size() -> usize {
         31457280
     }
+    const fn default_smtp_greeting_timeout() -> u64 {
+        300
+    }
+    const fn default_smtp_mail_timeout() -> u64 {
+        300
+    }
+    const fn default_smtp_rcpt_timeout() -> u64 {
+        300
+    }
+    const fn default_smtp_data_init_timeout() -> u64 {
+        120
+    }
+    const fn default_smtp_data_block_timeout() -> u64 {
+        180
+    }
+    const fn default_smtp_data_done_timeout() -> u64 {
+        600
+    }
+    const fn default_smtp_shutdown_timeout() -> u64 {
+        60
+    }
+    const fn default_smtp_max_sessions() -> NonZeroUsize {
+        NonZeroUsize::new(100).expect("100 != 0")
+    }
+    const fn default_smtp_max_transactions() -> NonZeroUsize {
+        NonZeroUsize::new(10).expect("10 != 0")
+    }
+    const fn default_postfix_reinject_pool_size() -> usize {
+        10
+    }
     const fn default_max_user_send_per_minute() -> NonZeroU32 {
         NonZeroU32::new(60).expect("60 != 0")
     }
@@ -104,3 +385,19 @@ impl Config {
         NonZeroU32::new(10).expect("10 != 0")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case("smtp_max_sessions")]
+    #[case("smtp_max_transactions")]
+    fn test_zero_limit(#[case] key: &str) {
+        // No session would be served or a complete message would wait forever.
+        let content = format!("[params]\nmail_domain = example.org\n{key} = 0\n");
+        let err = Config::parse(&content).unwrap_err();
+        assert!(err.to_string().contains("expected a nonzero usize"));
+    }
+}
@@ -0,0 +1,715 @@
+//! Parser for the arguments of `MAIL FROM` and `RCPT TO` (RFC 5321, section 4.1.2)
+//! and their ESMTP parameters.
+
+use crate::reply::SmtpReply;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Value of the `BODY` parameter (RFC 6152).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Body {
+    /// `BODY=7BIT`
+    SevenBit,
+    /// `BODY=8BITMIME`
+    EightBitMime,
+}
+
+impl fmt::Display for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SevenBit => write!(f, "7BIT"),
+            Self::EightBitMime => write!(f, "8BITMIME"),
+        }
+    }
+}
+
+/// Value of the `RET` parameter (RFC 3461, section 4.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ret {
+    /// Return the full message in a DSN.
+    Full,
+    /// Return only the headers in a DSN.
+    Headers,
+}
+
+impl fmt::Display for Ret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "FULL"),
+            Self::Headers => write!(f, "HDRS"),
+        }
+    }
+}
+
+/// Value of the `NOTIFY` parameter (RFC 3461, section 4.1).
+///
+/// `NOTIFY=NEVER` has all conditions unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Notify {
+    /// Request a DSN on successful delivery.
+    pub success: bool,
+    /// Request a DSN on delivery failure.
+    pub failure: bool,
+    /// Request a DSN if delivery is delayed.
+    pub delay: bool,
+}
+
+impl fmt::Display for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let conditions: Vec<&str> = [
+            (self.success, "SUCCESS"),
+            (self.failure, "FAILURE"),
+            (self.delay, "DELAY"),
+        ]
+        .into_iter()
+        .filter_map(|(set, condition)| set.then_some(condition))
+        .collect();
+        match conditions.is_empty() {
+            true => write!(f, "NEVER"),
+            false => write!(f, "{}", conditions.join(",")),
+        }
+    }
+}
+
+/// Parameters of the `MAIL FROM` command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailParameters {
+    /// Declared message size (RFC 1870).
+    pub size: Option<usize>,
+    /// Declared body type (RFC 6152).
+    pub body: Option<Body>,
+    /// Whether the message requires SMTPUTF8 support (RFC 6531).
+    pub smtputf8: bool,
+    /// Requested content of DSNs (RFC 3461).
+    pub ret: Option<Ret>,
+    /// Envelope identifier for DSNs as xtext (RFC 3461).
+    pub envid: Option<String>,
+}
+
+/// Formats the parameters as they follow the path, each preceded by a space.
+impl fmt::Display for MailParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(size) = self.size {
+            write!(f, " SIZE={size}")?;
+        }
+        if let Some(body) = self.body {
+            write!(f, " BODY={body}")?;
+        }
+        if self.smtputf8 {
+            write!(f, " SMTPUTF8")?;
+        }
+        if let Some(ret) = self.ret {
+            write!(f, " RET={ret}")?;
+        }
+        if let Some(envid) = &self.envid {
+            write!(f, " ENVID={envid}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parameters of the `RCPT TO` command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RcptParameters {
+    /// Conditions for sending DSNs (RFC 3461).
+    pub notify: Option<Notify>,
+    /// Original recipient as `addr-type;xtext` (RFC 3461).
+    pub orcpt: Option<String>,
+}
+
+/// Formats the parameters as they follow the path, each preceded by a space.
+impl fmt::Display for RcptParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(notify) = self.notify {
+            write!(f, " NOTIFY={notify}")?;
+        }
+        if let Some(orcpt) = &self.orcpt {
+            write!(f, " ORCPT={orcpt}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parsed arguments of the `MAIL FROM` command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailFrom {
+    /// Reverse-path, empty for the null reverse-path `<>`.
+    pub address: String,
+    /// ESMTP parameters.
+    pub parameters: MailParameters,
+}
+
+/// Parsed arguments of the `RCPT TO` command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RcptTo {
+    /// Forward-path.
+    pub address: String,
+    /// ESMTP parameters.
+    pub parameters: RcptParameters,
+}
+
+/// Parses the arguments following `MAIL FROM:`.
+///
+/// Returns the SMTP reply to send if the arguments are invalid.
+pub fn parse_mail_from(args: &str) -> Result<MailFrom, SmtpReply> {
+    let (path, params) = split_path(args)
+        .ok_or_else(|| SmtpReply::new(501, (5, 1, 7), "Invalid address in MAIL FROM"))?;
+    let address = match path {
+        "" => String::new(),
+        path => parse_mailbox(path)
+            .ok_or_else(|| SmtpReply::new(501, (5, 1, 7), "Invalid address in MAIL FROM"))?,
+    };
+
+    let mut parameters = MailParameters::default();
+    for (keyword, value) in split_parameters(params)? {
+        let duplicate = match (keyword.to_ascii_uppercase().as_str(), value) {
+            ("SIZE", Some(size)) => parameters
+                .size
+                .replace(
+                    size.parse()
+                        .map_err(|_| SmtpReply::new(501, (5, 5, 4), "Invalid SIZE parameter"))?,
+                )
+                .is_some(),
+            ("BODY", Some(body)) => {
+                let body = match body.to_ascii_uppercase().as_str() {
+                    "7BIT" => Body::SevenBit,
+                    "8BITMIME" => Body::EightBitMime,
+                    _ => {
+                        return Err(SmtpReply::new(
+                            555,
+                            (5, 5, 4),
+                            "Unsupported MAIL FROM parameter",
+                        ));
+                    }
+                };
+                parameters.body.replace(body).is_some()
+            }
+            ("SMTPUTF8", None) => std::mem::replace(&mut parameters.smtputf8, true),
+            ("RET", Some(ret)) => {
+                let ret = match ret.to_ascii_uppercase().as_str() {
+                    "FULL" => Ret::Full,
+                    "HDRS" => Ret::Headers,
+                    _ => return Err(SmtpReply::new(501, (5, 5, 4), "Invalid RET parameter")),
+                };
+                parameters.ret.replace(ret).is_some()
+            }
+            ("ENVID", Some(envid)) => {
+                // ENVID is limited to 100 characters (RFC 3461, section 4.4).
+                if envid.len() > 100 || !is_xtext(envid) {
+                    return Err(SmtpReply::new(501, (5, 5, 4), "Invalid ENVID parameter"));
+                }
+                parameters.envid.replace(envid.to_string()).is_some()
+            }
+            _ => {
+                return Err(SmtpReply::new(
+                    555,
+                    (5, 5, 4),
+                    "Unsupported MAIL FROM parameter",
+                ));
+            }
+        };
+        if duplicate {
+            return Err(SmtpReply::new(
+                501,
+                (5, 5, 4),
+                "Duplicate MAIL FROM parameter",
+            ));
+        }
+    }
+
+    if !address.is_ascii() && !parameters.smtputf8 {
+        return Err(SmtpReply::new(
+            553,
+            (5, 6, 7),
+            "Non-ASCII address requires SMTPUTF8",
+        ));
+    }
+
+    Ok(MailFrom {
+        address,
+        parameters,
+    })
+}
+
+/// Parses the arguments following `RCPT TO:`.
+///
+/// `smtputf8` tells if the transaction was started with the `SMTPUTF8` parameter.
+///
+/// Returns the SMTP reply to send if the arguments are invalid.
+pub fn parse_rcpt_to(args: &str, smtputf8: bool) -> Result<RcptTo, SmtpReply> {
+    let (path, params) = split_path(args)
+        .ok_or_else(|| SmtpReply::new(501, (5, 1, 3), "Invalid address in RCPT TO"))?;
+    let address = match path {
+        // The postmaster is reachable without a domain (RFC 5321, section 4.5.1).
+        path if path.eq_ignore_ascii_case("postmaster") => path.to_string(),
+        path => parse_mailbox(path)
+            .ok_or_else(|| SmtpReply::new(501, (5, 1, 3), "Invalid address in RCPT TO"))?,
+    };
+
+    let mut parameters = RcptParameters::default();
+    for (keyword, value) in split_parameters(params)? {
+        let duplicate = match (keyword.to_ascii_uppercase().as_str(), value) {
+            ("NOTIFY", Some(notify)) => {
+                let notify = parse_notify(notify)
+                    .ok_or_else(|| SmtpReply::new(501, (5, 5, 4), "Invalid NOTIFY parameter"))?;
+                parameters.notify.replace(notify).is_some()
+            }
+            ("ORCPT", Some(orcpt)) => {
+                if !is_orcpt(orcpt) {
+                    return Err(SmtpReply::new(501, (5, 5, 4), "Invalid ORCPT parameter"));
+                }
+                parameters.orcpt.replace(orcpt.to_string()).is_some()
+            }
+            _ => {
+                return Err(SmtpReply::new(
+                    555,
+                    (5, 5, 4),
+                    "Unsupported RCPT TO parameter",
+                ));
+            }
+        };
+        if duplicate {
+            return Err(SmtpReply::new(
+                501,
+                (5, 5, 4),
+                "Duplicate RCPT TO parameter",
+            ));
+        }
+    }
+
+    if !address.is_ascii() && !smtputf8 {
+        return Err(SmtpReply::new(
+            553,
+            (5, 6, 7),
+            "Non-ASCII address requires SMTPUTF8",
+        ));
+    }
+
+    Ok(RcptTo {
+        address,
+        parameters,
+    })
+}
+
+/// Splits `<path> parameters` into the content of the angle brackets and the parameters.
+///
+/// Leading whitespace before the path is tolerated, as sent by some clients.
+fn split_path(args: &str) -> Option<(&str, &str)> {
+    let path = args.trim_start().strip_prefix('<')?;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in path.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '>' if !quoted => return Some((path.get(..index)?, path.get(index + 1..)?)),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses `[A-d-l ":"] Mailbox` and returns the mailbox.
+///
+/// Source routes are ignored (RFC 5321, appendix C).
+/// The local part is kept as is because it is case-sensitive,
+/// the domain is converted to lowercase.
+fn parse_mailbox(path: &str) -> Option<String> {
+    let mailbox = if path.starts_with('@') {
+        let (route, mailbox) = path.split_once(':')?;
+        route
+            .split(',')
+            .all(|hop| hop.strip_prefix('@').is_some_and(is_domain))
+            .then_some(mailbox)?
+    } else {
+        path
+    };
+
+    // The domain can not contain `@`, but a quoted local part can.
+    let (local_part, domain) = mailbox.rsplit_once('@')?;
+    if !is_local_part(local_part) || !(is_domain(domain) || is_address_literal(domain)) {
+        return None;
+    }
+    Some(format!("{local_part}@{}", domain.to_lowercase()))
+}
+
+/// Checks for `Dot-string / Quoted-string`, with UTF-8 allowed by RFC 6531.
+fn is_local_part(local_part: &str) -> bool {
+    let Some(quoted) = local_part
+        .strip_prefix('"')
+        .and_then(|local_part| local_part.strip_suffix('"'))
+    else {
+        return local_part.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom.chars().all(|c| {
+                    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+                })
+        });
+    };
+
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        let valid = match c {
+            '\\' => chars.next().is_some_and(|c| matches!(c, ' '..='~')),
+            '"' => false,
+            c => matches!(c, ' '..='~') || !c.is_ascii(),
+        };
+        if !valid {
+            return false;
+        }
+    }
+    true
+}
+
+/// Checks for a domain name, U-labels of internationalized domain names are allowed.
+fn is_domain(domain: &str) -> bool {
+    domain.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || !c.is_ascii())
+    })
+}
+
+/// Checks for an IPv4 or IPv6 address literal such as `[192.0.2.1]` or `[IPv6:2001:db8::1]`.
+fn is_address_literal(domain: &str) -> bool {
+    let Some(literal) = domain
+        .strip_prefix('[')
+        .and_then(|domain| domain.strip_suffix(']'))
+    else {
+        return false;
+    };
+    match literal.split_once(':') {
+        Some((tag, address)) => {
+            tag.eq_ignore_ascii_case("IPv6") && address.parse::<Ipv6Addr>().is_ok()
+        }
+        None => literal.parse::<Ipv4Addr>().is_ok(),
+    }
+}
+
+/// Splits ESMTP parameters into keywords and optional values.
+fn split_parameters(params: &str) -> Result<Vec<(&str, Option<&str>)>, SmtpReply> {
+    if !params.is_empty() && !params.starts_with(' ') {
+        return Err(SmtpReply::new(501, (5, 5, 4), "Syntax error in parameters"));
+    }
+
+    params
+        .split(' ')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (keyword, value) = match param.split_once('=') {
+                Some((keyword, value)) => (keyword, Some(value)),
+                None => (param, None),
+            };
+            let valid_keyword = keyword.starts_with(|c: char| c.is_ascii_alphanumeric())
+                && keyword
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-');
+            let valid_value = value.is_none_or(|value| {
+                !value.is_empty()
+                    && value
+                        .chars()
+                        .all(|c| (matches!(c, '!'..='~') && c != '=') || !c.is_ascii())
+            });
+            match valid_keyword && valid_value {
+                true => Ok((keyword, value)),
+                false => Err(SmtpReply::new(501, (5, 5, 4), "Syntax error in parameters")),
+            }
+        })
+        .collect()
+}
+
+/// Parses `NEVER` or a comma-separated list of `SUCCESS`, `FAILURE` and `DELAY`.
+fn parse_notify(value: &str) -> Option<Notify> {
+    if value.eq_ignore_ascii_case("NEVER") {
+        return Some(Notify::default());
+    }
+
+    let mut notify = Notify::default();
+    for condition in value.split(',') {
+        let flag = match condition.to_ascii_uppercase().as_str() {
+            "SUCCESS" => &mut notify.success,
+            "FAILURE" => &mut notify.failure,
+            "DELAY" => &mut notify.delay,
+            _ => return None,
+        };
+        *flag = true;
+    }
+    Some(notify)
+}
+
+/// Checks for `addr-type ";" xtext`.
+fn is_orcpt(value: &str) -> bool {
+    value.split_once(';').is_some_and(|(addr_type, address)| {
+        !addr_type.is_empty()
+            && addr_type
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !address.is_empty()
+            && is_xtext(address)
+    })
+}
+
+/// Checks for xtext, where `+` and `=` are encoded as `+XX` (RFC 3461, section 4).
+fn is_xtext(value: &str) -> bool {
+    decode_xtext(value).is_some()
+}
+
+/// Encodes a value as xtext (RFC 3461, section 4).
+pub fn encode_xtext(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'!'..=b'~' if byte != b'+' && byte != b'=' => char::from(byte).to_string(),
+            _ => format!("+{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Decodes xtext (RFC 3461, section 4).
+///
+/// Returns `None` if the value is not valid xtext or does not decode to UTF-8.
+pub fn decode_xtext(value: &str) -> Option<String> {
+    let hex_digit = |c: char| match c {
+        '0'..='9' | 'A'..='F' => c.to_digit(16).and_then(|digit| u8::try_from(digit).ok()),
+        _ => None,
+    };
+
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let byte = match c {
+            '+' => hex_digit(chars.next()?)? << 4 | hex_digit(chars.next()?)?,
+            '!'..='~' if c != '=' => u8::try_from(c).ok()?,
+            _ => return None,
+        };
+        decoded.push(byte);
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case::simple("<t1@example.org>", "t1@example.org")]
+    #[case::null("<>", "")]
+    #[case::leading_space(" <t2@example.org>", "t2@example.org")]
+    #[case::case_preserved("<Alice.Smith@Example.ORG>", "Alice.Smith@example.org")]
+    #[case::srs(
+        "<SRS1=HHH=example.com==HHH=TT=example.org=alice@example.net>",
+        "SRS1=HHH=example.com==HHH=TT=example.org=alice@example.net"
+    )]
+    #[case::plus("<abc+alice@example.net>", "abc+alice@example.net")]
+    #[case::quoted("<\"john doe\"@example.org>", "\"john doe\"@example.org")]
+    #[case::quoted_special("<\"a@b>c\\\"d\"@example.org>", "\"a@b>c\\\"d\"@example.org")]
+    #[case::source_route(
+        "<@relay.example.com,@relay.example.net:bob@example.org>",
+        "bob@example.org"
+    )]
+    #[case::ipv4_literal("<postmaster@[192.0.2.1]>", "postmaster@[192.0.2.1]")]
+    #[case::ipv6_literal("<postmaster@[IPv6:2001:db8::1]>", "postmaster@[ipv6:2001:db8::1]")]
+    #[case::idn("<jörg@bücher.example> SMTPUTF8", "jörg@bücher.example")]
+    fn test_parse_mail_from_address(#[case] args: &str, #[case] expected: &str) {
+        assert_eq!(
+            parse_mail_from(args).map(|mail_from| mail_from.address),
+            Ok(expected.to_string())
+        );
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::no_brackets("t1@example.org")]
+    #[case::unterminated("<t1@example.org")]
+    #[case::no_domain("<t1>")]
+    #[case::empty_local_part("<@example.org>")]
+    #[case::double_dot("<a..b@example.org>")]
+    #[case::trailing_dot("<a.@example.org>")]
+    #[case::space_in_local_part("<a b@example.org>")]
+    #[case::unbalanced_quote("<\"ab@example.org>")]
+    #[case::bad_domain("<a@-example.org>")]
+    #[case::empty_label("<a@example..org>")]
+    #[case::bad_literal("<a@[300.0.0.1]>")]
+    #[case::bad_route("<relay.example.com:bob@example.org>")]
+    fn test_parse_mail_from_invalid_address(#[case] args: &str) {
+        assert_eq!(
+            parse_mail_from(args),
+            Err(SmtpReply::new(
+                501,
+                (5, 1, 7),
+                "Invalid address in MAIL FROM"
+            ))
+        );
+    }
+
+    #[rstest]
+    #[case::none("<t1@example.org>", MailParameters::default())]
+    #[case::size("<t1@example.org> SIZE=1024", MailParameters { size: Some(1024), ..Default::default() })]
+    #[case::body_smtputf8(
+        "<t1@example.org> BODY=8BITMIME SMTPUTF8",
+        MailParameters { body: Some(Body::EightBitMime), smtputf8: true, ..Default::default() }
+    )]
+    #[case::lowercase("<t1@example.org> body=7bit", MailParameters { body: Some(Body::SevenBit), ..Default::default() })]
+    #[case::dsn(
+        "<> RET=HDRS ENVID=QQ314159+2Bx",
+        MailParameters { ret: Some(Ret::Headers), envid: Some("QQ314159+2Bx".to_string()), ..Default::default() }
+    )]
+    #[case::extra_spaces("<t1@example.org>  RET=FULL", MailParameters { ret: Some(Ret::Full), ..Default::default() })]
+    fn test_parse_mail_from_parameters(#[case] args: &str, #[case] expected: MailParameters) {
+        assert_eq!(
+            parse_mail_from(args).map(|mail_from| mail_from.parameters),
+            Ok(expected)
+        );
+    }
+
+    #[rstest]
+    #[case::size("<t1@example.org> SIZE=big", "501 5.5.4 Invalid SIZE parameter")]
+    #[case::binarymime(
+        "<t1@example.org> BODY=BINARYMIME",
+        "555 5.5.4 Unsupported MAIL FROM parameter"
+    )]
+    #[case::smtputf8_value(
+        "<t1@example.org> SMTPUTF8=yes",
+        "555 5.5.4 Unsupported MAIL FROM parameter"
+    )]
+    #[case::unknown(
+        "<t1@example.org> OTHER=OTHER",
+        "555 5.5.4 Unsupported MAIL FROM parameter"
+    )]
+    #[case::ret("<t1@example.org> RET=ALL", "501 5.5.4 Invalid RET parameter")]
+    #[case::envid("<t1@example.org> ENVID=a+b", "501 5.5.4 Invalid ENVID parameter")]
+    #[case::duplicate(
+        "<t1@example.org> SIZE=1 SIZE=2",
+        "501 5.5.4 Duplicate MAIL FROM parameter"
+    )]
+    #[case::no_space("<t1@example.org>SIZE=1", "501 5.5.4 Syntax error in parameters")]
+    #[case::empty_value("<t1@example.org> SIZE=", "501 5.5.4 Syntax error in parameters")]
+    #[case::bad_keyword("<t1@example.org> -SIZE=1", "501 5.5.4 Syntax error in parameters")]
+    #[case::utf8_without_smtputf8(
+        "<jörg@example.org>",
+        "553 5.6.7 Non-ASCII address requires SMTPUTF8"
+    )]
+    fn test_parse_mail_from_error(#[case] args: &str, #[case] expected: &str) {
+        assert_eq!(
+            parse_mail_from(args).map_err(|e| e.to_string()),
+            Err(expected.to_string())
+        );
+    }
+
+    #[rstest]
+    #[case::plain("QQ314159", Some("QQ314159"))]
+    #[case::encoded("a+2Bb+3Dc", Some("a+b=c"))]
+    #[case::lowercase_hex("a+2bb", None)]
+    #[case::truncated("a+2", None)]
+    #[case::plus("a+b", None)]
+    #[case::equals("a=b", None)]
+    #[case::space("a b", None)]
+    fn test_decode_xtext(#[case] value: &str, #[case] expected: Option<&str>) {
+        assert_eq!(decode_xtext(value).as_deref(), expected);
+    }
+
+    #[rstest]
+    #[case::plain("mail.example.org", "mail.example.org")]
+    #[case::special("a+b=c d", "a+2Bb+3Dc+20d")]
+    #[case::utf8("bücher", "b+C3+BCcher")]
+    fn test_encode_xtext(#[case] value: &str, #[case] expected: &str) {
+        assert_eq!(encode_xtext(value), expected);
+        assert_eq!(decode_xtext(expected).as_deref(), Some(value));
+    }
+
+    #[rstest]
+    #[case::never("NEVER")]
+    #[case::success("SUCCESS")]
+    #[case::all("SUCCESS,FAILURE,DELAY")]
+    #[case::failure_delay("FAILURE,DELAY")]
+    fn test_notify_roundtrip(#[case] value: &str) {
+        assert_eq!(
+            parse_notify(value).map(|notify| notify.to_string()),
+            Some(value.to_string())
+        );
+    }
+
+    #[rstest]
+    #[case::none("<t1@example.org>")]
+    #[case::all("<t1@example.org> SIZE=1024 BODY=8BITMIME SMTPUTF8 RET=HDRS ENVID=QQ314159")]
+    #[case::seven_bit("<> BODY=7BIT RET=FULL")]
+    fn test_mail_parameters_roundtrip(#[case] args: &str) {
+        let mail_from = parse_mail_from(args).unwrap();
+        assert_eq!(
+            format!("<{}>{}", mail_from.address, mail_from.parameters),
+            args
+        );
+    }
+
+    #[rstest]
+    #[case::none("<t3@example.org>")]
+    #[case::all("<t3@example.org> NOTIFY=SUCCESS,DELAY ORCPT=rfc822;t3+2Bx@example.org")]
+    fn test_rcpt_parameters_roundtrip(#[case] args: &str) {
+        let rcpt_to = parse_rcpt_to(args, false).unwrap();
+        assert_eq!(format!("<{}>{}", rcpt_to.address, rcpt_to.parameters), args);
+    }
+
+    #[rstest]
+    #[case::simple("<t3@example.org>", false, Ok(("t3@example.org", RcptParameters::default())))]
+    #[case::notify(
+        "<t3@example.org> NOTIFY=SUCCESS,failure ORCPT=rfc822;t3+2Bx@example.org",
+        false,
+        Ok(("t3@example.org", RcptParameters {
+            notify: Some(Notify { success: true, failure: true, delay: false }),
+            orcpt: Some("rfc822;t3+2Bx@example.org".to_string()),
+        }))
+    )]
+    #[case::notify_never(
+        "<t3@example.org> NOTIFY=NEVER",
+        false,
+        Ok(("t3@example.org", RcptParameters { notify: Some(Notify::default()), orcpt: None }))
+    )]
+    #[case::utf8("<jörg@bücher.example>", true, Ok(("jörg@bücher.example", RcptParameters::default())))]
+    #[case::postmaster("<Postmaster>", false, Ok(("Postmaster", RcptParameters::default())))]
+    #[case::null("<>", false, Err("501 5.1.3 Invalid address in RCPT TO"))]
+    #[case::no_domain("<t3>", false, Err("501 5.1.3 Invalid address in RCPT TO"))]
+    #[case::notify_invalid(
+        "<t3@example.org> NOTIFY=NEVER,DELAY",
+        false,
+        Err("501 5.5.4 Invalid NOTIFY parameter")
+    )]
+    #[case::orcpt_invalid(
+        "<t3@example.org> ORCPT=t3@example.org",
+        false,
+        Err("501 5.5.4 Invalid ORCPT parameter")
+    )]
+    #[case::unknown(
+        "<t3@example.org> SIZE=1",
+        false,
+        Err("555 5.5.4 Unsupported RCPT TO parameter")
+    )]
+    #[case::duplicate(
+        "<t3@example.org> NOTIFY=DELAY NOTIFY=DELAY",
+        false,
+        Err("501 5.5.4 Duplicate RCPT TO parameter")
+    )]
+    #[case::utf8_without_smtputf8(
+        "<jörg@bücher.example>",
+        false,
+        Err("553 5.6.7 Non-ASCII address requires SMTPUTF8")
+    )]
+    fn test_parse_rcpt_to(
+        #[case] args: &str,
+        #[case] smtputf8: bool,
+        #[case] expected: Result<(&str, RcptParameters), &str>,
+    ) {
+        let result = parse_rcpt_to(args, smtputf8)
+            .map(|rcpt_to| (rcpt_to.address.clone(), rcpt_to.parameters))
+            .map_err(|e| e.to_string());
+        let expected = expected
+            .map(|(address, parameters)| (address.to_string(), parameters))
+            .map_err(ToString::to_string);
+        assert_eq!(result, expected);
+    }
+}
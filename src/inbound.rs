@@ -1,113 +1,372 @@
 //! Module for handling incoming SMTP messages.
 
-use crate::ENCRYPTION_NEEDED_523;
-use crate::config::Config;
-use crate::message::{check_encrypted, is_securejoin};
-use crate::smtp_server::SmtpHandler;
+use crate::config::{Config, Role};
+use crate::encryption_needed_523;
+use crate::message::{check_encrypted, is_mailer_daemon_report, is_securejoin};
+use crate::reinject::{Delivery, Reinjector};
+use crate::reply::SmtpReply;
+use crate::smtp_server::{Protocol, SmtpHandler};
+use crate::trace::{Trace, Verdict, strip_verdict};
+use crate::xclient::ClientInfo;
 use async_trait::async_trait;
-use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
-use mailparse::{MailHeaderMap, parse_mail};
+use mailparse::{ParsedMail, parse_mail};
 use std::sync::Arc;
 
 pub use crate::smtp_server::Envelope;
-use crate::utils::{extract_address, format_smtp_error};
 
 /// Handler for incoming SMTP messages.
 pub struct IncomingBeforeQueueHandler {
     config: Arc<Config>,
+    reinjector: Box<dyn Reinjector>,
+    trace: Option<Trace>,
 }
 
 impl IncomingBeforeQueueHandler {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Arc<Config>) -> Self {
+        let reinjector = config.reinjector(Role::Incoming);
+        let trace = config.trace(Role::Incoming);
         Self {
-            config: Arc::new(config),
+            config,
+            reinjector,
+            trace,
+        }
+    }
+
+    /// Checks the message content.
+    ///
+    /// The message can be delivered to every recipient
+    /// unless the verdict is [`Verdict::Cleartext`],
+    /// then only recipients allowing cleartext can receive it.
+    fn check_message(&self, envelope: &Envelope) -> Result<Verdict, SmtpReply> {
+        log::debug!(
+            "Processing DATA message from {} sent by {}",
+            envelope.mail_from,
+            envelope.client
+        );
+
+        let message = match parse_mail(&envelope.data) {
+            Ok(m) => m,
+            Err(e) => {
+                return Err(SmtpReply::new(
+                    554,
+                    (5, 6, 0),
+                    format!("Failed to parse message: {e}"),
+                ));
+            }
+        };
+
+        let verdict = verdict(&message, &envelope.mail_from);
+        log::debug!("verdict: {verdict}");
+        match verdict.is_encrypted() {
+            true => log::info!("Incoming: Filtering encrypted mail."),
+            false => log::info!("Incoming: Filtering unencrypted mail."),
         }
+        Ok(verdict)
+    }
+
+    /// Checks if a recipient accepts unencrypted mail.
+    fn check_cleartext_recipient(
+        &self,
+        envelope: &Envelope,
+        recipient: &str,
+    ) -> Result<(), SmtpReply> {
+        if self.config.is_cleartext_ok(recipient) {
+            return Ok(());
+        }
+
+        log::warn!(
+            "Rejected unencrypted mail from: {} to {recipient} sent by {}",
+            envelope.mail_from,
+            envelope.client
+        );
+        Err(encryption_needed_523())
     }
 }
 
 #[async_trait]
 impl SmtpHandler for IncomingBeforeQueueHandler {
-    fn handle_mail(&self, _address: &str) -> Result<(), String> {
+    fn handle_mail(&self, _address: &str, _client: &ClientInfo) -> Result<(), SmtpReply> {
         Ok(())
     }
 
-    fn check_data(&self, envelope: &Envelope) -> Result<(), String> {
-        log::debug!("Processing DATA message from {}", envelope.mail_from);
-
-        let message = match parse_mail(&envelope.data) {
-            Ok(m) => m,
-            Err(e) => return Err(format!("500 Failed to parse message: {}", e)),
-        };
+    fn handle_rcpt(&self, address: &str, envelope: &Envelope) -> Result<(), SmtpReply> {
+        // With LMTP, every recipient gets its own reply to the message.
+        if self.config.protocol(Role::Incoming) == Protocol::Lmtp {
+            return Ok(());
+        }
 
-        let mail_encrypted = check_encrypted(&message, false);
-        log::debug!("mail_encrypted: {mail_encrypted}");
-        log::debug!("is_securejoin: {}", is_securejoin(&message));
+        // The message is accepted or rejected for all recipients at once,
+        // so recipients that require encryption are not mixed with those allowing cleartext.
+        // The client retries the deferred recipients in a separate transaction,
+        // where unencrypted mail is rejected with 523 only for the recipients requiring encryption.
+        if let Some(first) = envelope.rcpt_to.first()
+            && self.config.is_cleartext_ok(first) != self.config.is_cleartext_ok(address)
+        {
+            return Err(SmtpReply::new(
+                452,
+                (4, 5, 3),
+                "Too many recipients with different encryption requirements",
+            ));
+        }
+        Ok(())
+    }
 
-        // Allow encrypted or securejoin messages
-        if mail_encrypted || is_securejoin(&message) {
-            log::info!("Incoming: Filtering encrypted mail.");
-            return Ok(());
+    fn check_data(&self, envelope: &Envelope) -> Result<Option<Verdict>, SmtpReply> {
+        let verdict = self.check_message(envelope)?;
+        if verdict == Verdict::Cleartext {
+            envelope
+                .rcpt_to
+                .iter()
+                .try_for_each(|recipient| self.check_cleartext_recipient(envelope, recipient))?;
         }
+        Ok(Some(verdict))
+    }
 
-        log::info!("Incoming: Filtering unencrypted mail.");
+    fn check_recipients(
+        &self,
+        envelope: &Envelope,
+    ) -> (Option<Verdict>, Vec<Result<(), SmtpReply>>) {
+        let check_message = self.check_message(envelope);
+        let verdicts = envelope
+            .rcpt_to
+            .iter()
+            .map(|recipient| match &check_message {
+                Ok(Verdict::Cleartext) => self.check_cleartext_recipient(envelope, recipient),
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.clone()),
+            })
+            .collect();
+        (check_message.ok(), verdicts)
+    }
 
+    async fn reinject_mail(
+        &self,
+        envelope: &Envelope,
+        delivery: Delivery,
+    ) -> Vec<Result<(), SmtpReply>> {
+        log::debug!("Re-injecting the mail that passed checks");
+        // Downstream filters may rely on the verdict, so a forged one is never passed on.
+        let stamped = match &self.trace {
+            Some(trace) => Some(trace.stamp(envelope)),
+            None => strip_verdict(envelope),
+        };
+        let envelope = stamped.as_ref().unwrap_or(envelope);
+        self.reinjector.reinject(envelope, delivery).await
+    }
+}
+
+/// Tells why an incoming message is accepted.
+fn verdict(message: &ParsedMail, mail_from: &str) -> Verdict {
+    if check_encrypted(message, false) {
+        Verdict::Encrypted
+    } else if is_securejoin(message) {
+        Verdict::Securejoin
+    } else if is_mailer_daemon_report(message, mail_from) {
         // Allow cleartext mailer-daemon messages
-        if let Some(auto_submitted) = message.headers.get_first_value("Auto-Submitted")
-            && !auto_submitted.is_empty()
-        {
-            let from_header = message
-                .headers
-                .get_first_value("From")
-                .unwrap_or_default()
-                .trim()
-                .to_string();
-
-            if let Some(from_addr) = extract_address(&from_header)
-                && from_addr.to_lowercase().starts_with("mailer-daemon@")
-                && message.ctype.mimetype == "multipart/report"
-            {
-                return Ok(());
-            }
+        Verdict::MailerDaemon
+    } else {
+        Verdict::Cleartext
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reinject::MemoryReinjector;
+    use rstest::*;
+    use testresult::TestResult;
+
+    /// Recipient of the test domain requiring encryption.
+    const ENFORCING: &str = "enforcing@example.org";
+
+    /// Recipient of the test domain allowing cleartext.
+    const CLEARTEXT: &str = "cleartext@example.org";
+
+    /// Creates a handler with mailboxes in an empty directory, where only [`ENFORCING`] requires encryption.
+    fn test_handler(name: &str, lmtp: bool) -> TestResult<IncomingBeforeQueueHandler> {
+        let dir =
+            std::env::temp_dir().join(format!("filtermail-inbound-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(ENFORCING))?;
+        std::fs::write(dir.join(ENFORCING).join("enforceE2EEincoming"), b"")?;
+
+        let config = Config::parse(&format!(
+            "[params]\n\
+             mail_domain = example.org\n\
+             mailboxes_dir = {}\n\
+             filtermail_lmtp_incoming = {lmtp}\n",
+            dir.display()
+        ))?;
+        Ok(IncomingBeforeQueueHandler {
+            config: Arc::new(config),
+            reinjector: Box::new(MemoryReinjector::default()),
+            trace: None,
+        })
+    }
+
+    /// Adds the recipients like the server does, returning the reply to each `RCPT TO`.
+    fn add_recipients(
+        handler: &IncomingBeforeQueueHandler,
+        envelope: &mut Envelope,
+        recipients: &[&str],
+    ) -> Vec<Result<(), SmtpReply>> {
+        recipients
+            .iter()
+            .map(|&address| {
+                handler.handle_rcpt(address, envelope)?;
+                envelope.rcpt_to.push(address.to_string());
+                envelope.rcpt_parameters.push(Default::default());
+                Ok(())
+            })
+            .collect()
+    }
+
+    fn plain_envelope() -> Envelope {
+        Envelope {
+            mail_from: "alice@example.net".to_string(),
+            data: include_bytes!("../test_data/plain.eml").to_vec(),
+            ..Default::default()
         }
+    }
 
-        for recipient in &envelope.rcpt_to {
-            if !self.config.is_cleartext_ok(recipient) {
-                log::warn!("Rejected unencrypted mail from: {}", envelope.mail_from);
-                return Err(ENCRYPTION_NEEDED_523.to_string());
-            }
+    #[rstest]
+    #[case::enforcing_first(&[ENFORCING, CLEARTEXT])]
+    #[case::cleartext_first(&[CLEARTEXT, ENFORCING])]
+    #[tokio::test]
+    async fn test_mixed_recipients_smtp(#[case] recipients: &[&str]) -> TestResult {
+        let handler = test_handler(&format!("smtp-{}", recipients[0]), false)?;
+        let deferred = SmtpReply::new(
+            452,
+            (4, 5, 3),
+            "Too many recipients with different encryption requirements",
+        );
+
+        // The second group is deferred, so the message is checked against the first one only.
+        let mut envelope = plain_envelope();
+        let replies = add_recipients(&handler, &mut envelope, recipients);
+        assert_eq!(replies, [Ok(()), Err(deferred)]);
+        assert_eq!(envelope.rcpt_to, recipients[..1]);
+        let expected = match recipients[0] {
+            ENFORCING => Err(encryption_needed_523()),
+            _ => Ok(Some(Verdict::Cleartext)),
+        };
+        assert_eq!(handler.check_data(&envelope), expected);
+
+        // The client retries the deferred recipient in a new transaction.
+        let mut envelope = plain_envelope();
+        let replies = add_recipients(&handler, &mut envelope, &recipients[1..]);
+        assert_eq!(replies, [Ok(())]);
+        let expected = match recipients[1] {
+            ENFORCING => Err(encryption_needed_523()),
+            _ => Ok(Some(Verdict::Cleartext)),
+        };
+        assert_eq!(handler.check_data(&envelope), expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mixed_recipients_lmtp() -> TestResult {
+        let handler = test_handler("lmtp", true)?;
+
+        let mut envelope = plain_envelope();
+        let replies = add_recipients(&handler, &mut envelope, &[ENFORCING, CLEARTEXT]);
+        assert_eq!(replies, [Ok(()), Ok(())]);
+        assert_eq!(
+            handler.check_recipients(&envelope),
+            (
+                Some(Verdict::Cleartext),
+                vec![Err(encryption_needed_523()), Ok(())]
+            )
+        );
+
+        // Only the accepted recipient gets the message.
+        let accepted = envelope.with_recipients([false, true]);
+        let outcomes = handler
+            .reinject_mail(&accepted, Delivery::PerRecipient)
+            .await;
+        assert_eq!(outcomes, [Ok(())]);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::bounce("test_data/mailer-daemon.eml", Ok(Some(Verdict::MailerDaemon)))]
+    #[case::mdn("test_data/mdn.eml", Err(encryption_needed_523()))]
+    #[tokio::test]
+    async fn test_null_sender_report(
+        #[case] file: &str,
+        #[case] expected: Result<Option<Verdict>, SmtpReply>,
+    ) -> TestResult {
+        let handler = test_handler(
+            &format!("report-{}", file.trim_start_matches("test_data/")),
+            false,
+        )?;
+        let mut envelope = Envelope {
+            data: std::fs::read(file)?,
+            ..Default::default()
+        };
+        add_recipients(&handler, &mut envelope, &[ENFORCING]);
+        assert_eq!(handler.check_data(&envelope), expected);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::trace(true)]
+    #[case::no_trace(false)]
+    #[tokio::test]
+    async fn test_spoofed_verdict(#[case] trace: bool) -> TestResult {
+        let mut handler = test_handler(&format!("spoofed-{trace}"), false)?;
+        let reinjector = Arc::new(MemoryReinjector::default());
+        handler.reinjector = Box::new(reinjector.clone());
+        if trace {
+            handler.trace = Some(Trace::new("example.org", Role::Incoming, Protocol::Smtp));
         }
 
+        let mut envelope = plain_envelope();
+        envelope.data = [b"X-Filtermail-Verdict: encrypted\r\n", &envelope.data[..]].concat();
+        add_recipients(&handler, &mut envelope, &[CLEARTEXT]);
+        envelope.verdict = handler.check_data(&envelope).unwrap();
+        let outcomes = handler
+            .reinject_mail(&envelope, Delivery::AllOrNothing)
+            .await;
+        assert_eq!(outcomes, [Ok(())]);
+
+        let data = String::from_utf8(reinjector.envelopes()[0].data.clone())?;
+        assert!(!data.contains("X-Filtermail-Verdict: encrypted"));
+        assert_eq!(data.contains("X-Filtermail-Verdict: cleartext\r\n"), trace);
+        assert!(data.contains("Meow!"));
         Ok(())
     }
 
-    async fn reinject_mail(&self, envelope: &Envelope) -> Result<(), String> {
-        log::debug!("Re-injecting the mail that passed checks");
+    #[tokio::test]
+    async fn test_cleartext_to_postmaster() -> TestResult {
+        let handler = test_handler("postmaster", false)?;
+        let mut envelope = plain_envelope();
+        let replies = add_recipients(&handler, &mut envelope, &[CLEARTEXT, "Postmaster"]);
+        assert_eq!(replies, [Ok(()), Ok(())]);
+        assert_eq!(handler.check_data(&envelope), Ok(Some(Verdict::Cleartext)));
+        let outcomes = handler
+            .reinject_mail(&envelope, Delivery::AllOrNothing)
+            .await;
+        assert_eq!(outcomes, [Ok(()), Ok(())]);
+        Ok(())
+    }
 
-        let mailer = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("localhost")
-            .port(self.config.postfix_reinject_port_incoming)
-            .build();
-
-        let envelope_data = lettre::address::Envelope::new(
-            Some(
-                envelope
-                    .mail_from
-                    .parse()
-                    .map_err(|e| format!("Invalid from address: {}", e))?,
-            ),
-            envelope
-                .rcpt_to
-                .iter()
-                .map(|addr| addr.parse())
-                .collect::<Result<Vec<_>, _>>()
-                .map_err(|e| format!("Invalid to address: {}", e))?,
-        )
-        .map_err(|e| format!("Failed to create envelope: {}", e))?;
+    #[tokio::test]
+    async fn test_cleartext_recipient_after_retry() -> TestResult {
+        let handler = test_handler("retry", false)?;
 
-        mailer
-            .send_raw(&envelope_data, &envelope.data)
-            .await
-            .map_err(format_smtp_error)?;
+        let mut envelope = plain_envelope();
+        add_recipients(&handler, &mut envelope, &[ENFORCING, CLEARTEXT]);
+        assert_eq!(handler.check_data(&envelope), Err(encryption_needed_523()));
 
+        let mut envelope = plain_envelope();
+        let replies = add_recipients(&handler, &mut envelope, &[CLEARTEXT]);
+        assert_eq!(replies, [Ok(())]);
+        assert_eq!(handler.check_data(&envelope), Ok(Some(Verdict::Cleartext)));
+        let outcomes = handler
+            .reinject_mail(&envelope, Delivery::AllOrNothing)
+            .await;
+        assert_eq!(outcomes, [Ok(())]);
         Ok(())
     }
 }
@@ -0,0 +1,259 @@
+//! Listening sockets for the SMTP server.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+
+/// Address to listen on for SMTP connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// TCP socket on an IPv4 or IPv6 address.
+    Tcp(SocketAddr),
+    /// Unix domain socket at a filesystem path.
+    Unix {
+        /// Path of the socket file.
+        path: PathBuf,
+        /// Permission bits applied to the socket file.
+        mode: Option<u32>,
+        /// Owner applied to the socket file, as `user` or `user:group`.
+        owner: Option<String>,
+    },
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A connection accepted by a [`Listener`].
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+/// TCP or Unix domain socket listener.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds the address, replacing a stale Unix socket left by a previous run.
+    pub async fn bind(addr: &ListenAddr) -> std::io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            ListenAddr::Unix { path, mode, owner } => {
+                remove_stale_socket(path)?;
+                // The socket is bound in a directory only this process can enter
+                // and moved into place once its mode and owner are set,
+                // so nobody can connect to it with the permissions given by the umask.
+                let dir = private_dir(path)?;
+                let result = bind_private(&dir, path, *mode, owner.as_deref());
+                std::fs::remove_dir_all(&dir)?;
+                Ok(Self::Unix(result?))
+            }
+        }
+    }
+
+    /// Accepts a new connection.
+    ///
+    /// Returns the address of the peer along with it, `None` for a Unix domain socket.
+    pub async fn accept(&self) -> std::io::Result<(Box<dyn Connection>, Option<IpAddr>)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (socket, peer) = listener.accept().await?;
+                // Disable Nagle's algorithm.
+                socket.set_nodelay(true)?;
+                Ok((Box::new(socket), Some(peer.ip())))
+            }
+            Self::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Box::new(socket), None))
+            }
+        }
+    }
+}
+
+/// Binds a socket in `dir` with the mode and owner applied, then renames it to `path`.
+fn bind_private(
+    dir: &Path,
+    path: &Path,
+    mode: Option<u32>,
+    owner: Option<&str>,
+) -> std::io::Result<UnixListener> {
+    let private = dir.join("socket");
+    let listener = UnixListener::bind(&private)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(mode))?;
+    }
+    if let Some(owner) = owner {
+        let (uid, gid) = resolve_owner(owner)?;
+        std::os::unix::fs::chown(&private, Some(uid), gid)?;
+    }
+    std::fs::rename(&private, path)?;
+    Ok(listener)
+}
+
+/// Creates a directory next to `path` that only the owner can access.
+fn private_dir(path: &Path) -> std::io::Result<PathBuf> {
+    let name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        )
+    })?;
+    let mut private = std::ffi::OsString::from(".");
+    private.push(name);
+    private.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(private);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// Removes a socket file at the path, binding fails if another kind of file is there.
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Resolves `user` or `user:group` to numeric ids.
+///
+/// Names are looked up in `/etc/passwd` and `/etc/group`, numeric ids are used as is.
+fn resolve_owner(owner: &str) -> std::io::Result<(u32, Option<u32>)> {
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner, None),
+    };
+
+    let uid = resolve_id(user, "/etc/passwd")?;
+    let gid = group
+        .map(|group| resolve_id(group, "/etc/group"))
+        .transpose()?;
+    Ok((uid, gid))
+}
+
+/// Resolves a user or group name to its id using a passwd-style database.
+fn resolve_id(name: &str, database: &str) -> std::io::Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let content = std::fs::read_to_string(database)?;
+    find_id(&content, name).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{name} not found in {database}"),
+        )
+    })
+}
+
+/// Finds the id of `name` in the content of `/etc/passwd` or `/etc/group`.
+fn find_id(content: &str, name: &str) -> Option<u32> {
+    content.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+    use testresult::TestResult;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[rstest]
+    #[case("root", Some(0))]
+    #[case("postfix", Some(105))]
+    #[case("vmail", None)]
+    #[case("post", None)]
+    fn test_find_id(#[case] name: &str, #[case] expected: Option<u32>) {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\n\
+                      postfix:x:105:112::/var/spool/postfix:/usr/sbin/nologin\n";
+        assert_eq!(find_id(passwd, name), expected);
+    }
+
+    #[test]
+    fn test_resolve_owner_numeric() -> TestResult {
+        assert_eq!(resolve_owner("1000")?, (1000, None));
+        assert_eq!(resolve_owner("1000:2000")?, (1000, Some(2000)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix() -> TestResult {
+        let dir = std::env::temp_dir().join(format!("filtermail-listener-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("filtermail.sock");
+        let metadata = std::fs::metadata(&dir)?;
+        let owner = format!(
+            "{}:{}",
+            std::os::unix::fs::MetadataExt::uid(&metadata),
+            std::os::unix::fs::MetadataExt::gid(&metadata)
+        );
+        let addr = ListenAddr::Unix {
+            path: path.clone(),
+            mode: Some(0o660),
+            owner: Some(owner),
+        };
+
+        // Binding again replaces the socket left by the first listener.
+        drop(Listener::bind(&addr).await?);
+        let listener = Listener::bind(&addr).await?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        // The directory the socket was bound in is gone.
+        assert!(
+            !dir.join(format!(".filtermail.sock.{}", std::process::id()))
+                .exists()
+        );
+
+        let mut client = UnixStream::connect(&path).await?;
+        let (mut server, peer) = listener.accept().await?;
+        assert_eq!(peer, None);
+        client.write_all(b"ping").await?;
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_keeps_regular_file() -> TestResult {
+        let dir = std::env::temp_dir().join(format!("filtermail-listener-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("not-a-socket");
+        std::fs::write(&path, "data")?;
+        let addr = ListenAddr::Unix {
+            path: path.clone(),
+            mode: None,
+            owner: None,
+        };
+
+        assert!(Listener::bind(&addr).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path)?, "data");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
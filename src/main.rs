@@ -24,28 +24,187 @@
     clippy::format_push_string,
     clippy::bool_to_int_with_if
 )]
+pub(crate) mod bounce;
 mod config;
 pub(crate) mod error;
+pub(crate) mod esmtp;
 pub(crate) mod inbound;
+pub(crate) mod listener;
 pub(crate) mod message;
 pub(crate) mod openpgp;
 pub(crate) mod outbound;
+pub(crate) mod reinject;
+pub(crate) mod reply;
 pub(crate) mod smtp_server;
+pub(crate) mod spool;
+pub(crate) mod systemd;
+pub(crate) mod trace;
 pub(crate) mod utils;
+pub(crate) mod xclient;
 
-use config::Config;
+use config::{Config, Role};
 use env_logger::Env;
 use inbound::IncomingBeforeQueueHandler;
+use listener::{ListenAddr, Listener};
 use outbound::OutgoingBeforeQueueHandler;
+use reply::SmtpReply;
 use smtp_server::run_smtp_server;
 use std::env;
 use std::process;
 use std::sync::Arc;
+use systemd::{ActivatedListeners, Notifier};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 
-const ENCRYPTION_NEEDED_523: &str = "523 Encryption Needed: Invalid Unencrypted Mail";
+/// Reply rejecting unencrypted mail to or from a recipient that requires encryption.
+fn encryption_needed_523() -> SmtpReply {
+    SmtpReply::new(
+        523,
+        (5, 7, 1),
+        "Encryption Needed: Invalid Unencrypted Mail",
+    )
+}
+
+/// Waits for SIGTERM or SIGINT.
+async fn wait_for_signal() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok(()),
+        result = tokio::signal::ctrl_c() => result,
+    }
+}
+
+/// Uses the socket passed by systemd for the role, or binds `addr` if there is none.
+async fn listen(
+    activated: &mut ActivatedListeners,
+    role: &str,
+    addr: &ListenAddr,
+) -> std::io::Result<Listener> {
+    let passed = match addr {
+        ListenAddr::Tcp(_) => activated.take_tcp(role)?.map(|listener| {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener).map(Listener::Tcp)
+        }),
+        ListenAddr::Unix { .. } => activated.take_unix(role)?.map(|listener| {
+            listener.set_nonblocking(true)?;
+            UnixListener::from_std(listener).map(Listener::Unix)
+        }),
+    };
+
+    match passed {
+        Some(listener) => {
+            log::info!("Using socket passed by systemd for {role} mode");
+            listener
+        }
+        None => Listener::bind(addr).await,
+    }
+}
+
+/// Serves the filter role on the listener until shutdown.
+async fn serve(
+    role: Role,
+    config: Arc<Config>,
+    listener: Listener,
+    shutdown: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    let protocol = config.protocol(role);
+    let max_size = config.max_message_size;
+    let timeouts = config.smtp_timeouts();
+    let limits = config.smtp_limits();
+    let xclient_hosts = config.filtermail_xclient_hosts.clone();
+    if let Some(spool) = config.spool(role) {
+        tokio::spawn(spool.run(config.reinject_target(role), shutdown.clone()));
+    }
+    match role {
+        Role::Incoming => {
+            let handler = Arc::new(IncomingBeforeQueueHandler::new(config));
+            run_smtp_server(
+                listener,
+                handler,
+                protocol,
+                max_size,
+                timeouts,
+                limits,
+                xclient_hosts,
+                shutdown,
+            )
+            .await
+        }
+        Role::Outgoing => {
+            let handler = Arc::new(OutgoingBeforeQueueHandler::new(config));
+            run_smtp_server(
+                listener,
+                handler,
+                protocol,
+                max_size,
+                timeouts,
+                limits,
+                xclient_hosts,
+                shutdown,
+            )
+            .await
+        }
+    }
+}
+
+/// Reads the config file, exiting if that fails.
+fn read_config(path: &str) -> Config {
+    match Config::from_file(path) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to read config: {}", e);
+            process::exit(1);
+        }
+    }
+}
 
+/// Lists the spooled messages of all roles or makes them due for delivery.
+async fn spool(config: &Config, action: &str) -> std::io::Result<()> {
+    for role in Role::ALL {
+        let Some(spool) = config.spool(role) else {
+            return Err(std::io::Error::other("filtermail_spool_dir is not set"));
+        };
+        match action {
+            "list" => {
+                for message in spool.list().await? {
+                    println!("{role} {message}");
+                }
+            }
+            "flush" => {
+                let count = spool.flush().await?;
+                println!("{role}: {count} messages flushed");
+            }
+            _ => {
+                return Err(std::io::Error::other(
+                    "spool command must be 'list' or 'flush'",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends a state to systemd, if running under it.
+fn notify(notifier: Option<&Notifier>, state: &str) {
+    if let Some(notifier) = notifier
+        && let Err(e) = notifier.notify(state)
+    {
+        log::warn!("Failed to notify systemd of {state}: {e}");
+    }
+}
+
+fn main() {
+    // Taking the passed sockets changes the environment,
+    // which is only sound before the runtime starts its threads.
+    let activated = ActivatedListeners::from_env();
+    run(activated);
+}
+
+/// Runs the command given on the command line.
 #[tokio::main]
-async fn main() {
+async fn run(activated: ActivatedListeners) {
     // default to info level
     let env = Env::new().filter_or("RUST_LOG", "info");
     env_logger::Builder::from_env(env)
@@ -54,12 +213,12 @@ async fn main() {
         .init();
 
     let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        eprintln!(
-            "Usage: {} <config_file> <mode>",
-            args.first().unwrap_or(&"filtermail".to_string())
-        );
-        eprintln!("  mode: incoming or outgoing");
+    let spool_command = args.len() == 4 && args.get(2).is_some_and(|mode| mode == "spool");
+    if args.len() != 3 && !spool_command {
+        let program = args.first().map_or("filtermail", String::as_str);
+        eprintln!("Usage: {program} <config_file> <mode>");
+        eprintln!("       {program} <config_file> spool (list|flush)");
+        eprintln!("  mode: incoming, outgoing or all");
         process::exit(1);
     }
 
@@ -70,36 +229,83 @@ async fn main() {
         unreachable!("args length checked above")
     };
 
-    if mode != "incoming" && mode != "outgoing" {
-        eprintln!("Error: mode must be 'incoming' or 'outgoing'");
-        process::exit(1);
+    if spool_command {
+        let config = read_config(config_path);
+        let action = args.get(3).map_or("", String::as_str);
+        if let Err(e) = spool(&config, action).await {
+            eprintln!("Error: {e}");
+            process::exit(1);
+        }
+        return;
     }
 
-    let config = match Config::from_file(config_path) {
-        Ok(c) => c,
+    let roles = match mode.as_str() {
+        "all" => Role::ALL.to_vec(),
+        mode => match mode.parse() {
+            Ok(role) => vec![role],
+            Err(_) => {
+                eprintln!("Error: mode must be 'incoming', 'outgoing' or 'all'");
+                process::exit(1);
+            }
+        },
+    };
+
+    let config = Arc::new(read_config(config_path));
+
+    let role_names: Vec<&str> = roles.iter().map(|role| role.name()).collect();
+    let mut activated = activated.for_roles(&role_names);
+    let notifier = match Notifier::from_env() {
+        Ok(notifier) => notifier.map(Arc::new),
         Err(e) => {
-            eprintln!("Failed to read config: {}", e);
-            process::exit(1);
+            log::warn!("Failed to connect to systemd notification socket: {e}");
+            None
         }
     };
 
-    if mode == "outgoing" {
-        let handler = Arc::new(OutgoingBeforeQueueHandler::new(config.clone()));
-        let addr = format!("127.0.0.1:{}", config.filtermail_smtp_port);
-        let max_size = config.max_message_size;
-        log::debug!("Outgoing SMTP server listening on {addr}");
+    if let Some(notifier) = notifier.clone()
+        && let Some(interval) = systemd::watchdog_interval()
+    {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                notify(Some(&notifier), "WATCHDOG=1");
+            }
+        });
+    }
 
-        if let Err(e) = run_smtp_server(&addr, handler, max_size).await {
-            eprintln!("Server error: {}", e);
-            process::exit(1);
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let shutdown_notifier = notifier.clone();
+    tokio::spawn(async move {
+        match wait_for_signal().await {
+            Ok(()) => log::info!("Received shutdown signal."),
+            Err(e) => {
+                log::error!("Failed to listen for shutdown signals: {e}");
+                return;
+            }
         }
-    } else {
-        let handler = Arc::new(IncomingBeforeQueueHandler::new(config.clone()));
-        let addr = format!("127.0.0.1:{}", config.filtermail_smtp_port_incoming);
-        let max_size = config.max_message_size;
-        log::debug!("Incoming SMTP server listening on {addr}");
+        notify(shutdown_notifier.as_deref(), "STOPPING=1");
+        shutdown_tx.send_replace(true);
+    });
+
+    let mut servers = JoinSet::new();
+    for role in roles {
+        let addr = config.listen_addr(role);
+        let listener = match listen(&mut activated, role.name(), &addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Failed to listen on {addr}: {}", e);
+                process::exit(1);
+            }
+        };
+        log::debug!("SMTP server for {role} mail listening on {addr}");
+        servers.spawn(serve(role, config.clone(), listener, shutdown.clone()));
+    }
+    notify(notifier.as_deref(), "READY=1");
 
-        if let Err(e) = run_smtp_server(&addr, handler, max_size).await {
+    while let Some(result) = servers.join_next().await {
+        let result = result.unwrap_or_else(|e| Err(std::io::Error::other(e)));
+        if let Err(e) = result {
             eprintln!("Server error: {}", e);
             process::exit(1);
         }
@@ -1,6 +1,7 @@
 //! Message-related checks.
 
 use crate::openpgp::check_armored_payload;
+use crate::utils::extract_address;
 use mailparse::MailHeaderMap;
 
 /// Check if message is a secure-join message (vc-request or vg-request)
@@ -131,13 +132,46 @@ pub fn check_encrypted(mail: &mailparse::ParsedMail, outgoing: bool) -> bool {
     true
 }
 
-/// Check if recipient matches a passthrough pattern
+/// Check if message is an automatic report such as a bounce (RFC 3464) or an MDN (RFC 8098)
+/// sent by a mailer-daemon, or a bounce sent with the null reverse-path.
+pub fn is_mailer_daemon_report(mail: &mailparse::ParsedMail, mail_from: &str) -> bool {
+    let auto_submitted = mail
+        .headers
+        .get_first_value("Auto-Submitted")
+        .unwrap_or_default();
+    if auto_submitted.is_empty() || mail.ctype.mimetype != "multipart/report" {
+        return false;
+    }
+
+    // Bounces from remote MTAs use the null reverse-path.
+    // Anyone can send an MDN with it, so these are not let through.
+    if mail_from.is_empty() {
+        return mail
+            .ctype
+            .params
+            .get("report-type")
+            .is_some_and(|report_type| report_type.eq_ignore_ascii_case("delivery-status"));
+    }
+
+    let from_header = mail
+        .headers
+        .get_first_value("From")
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    extract_address(&from_header)
+        .is_some_and(|from_addr| from_addr.to_lowercase().starts_with("mailer-daemon@"))
+}
+
+/// Check if recipient matches a passthrough pattern, ignoring case
 pub fn recipient_matches_passthrough(recipient: &str, passthrough_recipients: &[String]) -> bool {
+    let recipient = recipient.to_lowercase();
     for addr in passthrough_recipients {
+        let addr = addr.to_lowercase();
         if recipient == addr {
             return true;
         }
-        if addr.starts_with('@') && recipient.ends_with(addr) {
+        if addr.starts_with('@') && recipient.ends_with(&addr) {
             return true;
         }
     }
@@ -190,9 +224,28 @@ mod tests {
         Ok(())
     }
 
+    #[rstest]
+    #[case::mailer_daemon("test_data/mailer-daemon.eml", "mailer-daemon@example.org", true)]
+    #[case::mailer_daemon_null_sender("test_data/mailer-daemon.eml", "", true)]
+    #[case::mdn("test_data/mdn.eml", "one@example.org", false)]
+    #[case::mdn_null_sender("test_data/mdn.eml", "", false)]
+    #[case::plain_null_sender("test_data/plain.eml", "", false)]
+    #[case::encrypted_null_sender("test_data/encrypted.eml", "", false)]
+    fn test_is_mailer_daemon_report(
+        #[case] file: &str,
+        #[case] mail_from: &str,
+        #[case] expected: bool,
+    ) -> TestResult {
+        let raw_email = std::fs::read_to_string(file)?;
+        let parsed = parse_mail(raw_email.as_bytes())?;
+        assert_eq!(is_mailer_daemon_report(&parsed, mail_from), expected);
+        Ok(())
+    }
+
     #[rstest]
     #[case("pass@example.org", true)]
     #[case("other@example.org", false)]
+    #[case("Pass@example.org", true)]
     #[case("anything@example.com", true)]
     #[case("anything@sub.example.com", false)]
     fn test_recipient_matches_passthrough(
@@ -1,48 +1,108 @@
 //! Module for handling outgoing SMTP messages.
 
-use crate::ENCRYPTION_NEEDED_523;
-use crate::config::Config;
+use crate::config::{Config, Role};
+use crate::encryption_needed_523;
 use crate::message::{check_encrypted, is_securejoin, recipient_matches_passthrough};
+use crate::reinject::{Delivery, Reinjector};
+use crate::reply::SmtpReply;
 pub use crate::smtp_server::Envelope;
 use crate::smtp_server::SmtpHandler;
-use crate::utils::{extract_address, format_smtp_error};
+use crate::trace::{Trace, Verdict};
+use crate::utils::extract_address;
+use crate::xclient::ClientInfo;
 use async_trait::async_trait;
 use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
-use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
-use mailparse::{MailHeaderMap, parse_mail};
+use mailparse::{MailHeaderMap, ParsedMail, parse_mail};
 use std::sync::Arc;
 
 /// Handler for outgoing SMTP messages.
 pub struct OutgoingBeforeQueueHandler {
     config: Arc<Config>,
     send_rate_limiter: DefaultKeyedRateLimiter<String>,
+    reinjector: Box<dyn Reinjector>,
+    trace: Option<Trace>,
 }
 
 impl OutgoingBeforeQueueHandler {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Arc<Config>) -> Self {
         let quota = Quota::per_minute(config.max_user_send_per_minute)
             .allow_burst(config.max_user_send_burst_size);
         Self {
-            config: Arc::new(config),
+            reinjector: config.reinjector(Role::Outgoing),
+            trace: config.trace(Role::Outgoing),
+            config,
             send_rate_limiter: RateLimiter::keyed(quota),
         }
     }
+
+    /// Tells why an outgoing message is accepted, `None` if it must be rejected.
+    fn verdict(&self, message: &ParsedMail, envelope: &Envelope) -> Option<Verdict> {
+        // Allow encrypted or securejoin messages
+        if check_encrypted(message, true) {
+            return Some(Verdict::Encrypted);
+        }
+        if is_securejoin(message) {
+            return Some(Verdict::Securejoin);
+        }
+
+        // Allow passthrough senders
+        if self
+            .config
+            .passthrough_senders
+            .iter()
+            .any(|sender| sender.eq_ignore_ascii_case(&envelope.mail_from))
+        {
+            return Some(Verdict::Passthrough);
+        }
+
+        // Allow self-sent Autocrypt Setup Message
+        if envelope.rcpt_to.len() == 1
+            && let Some(rcpt_to) = envelope.rcpt_to.first()
+            && rcpt_to.eq_ignore_ascii_case(&envelope.mail_from)
+        {
+            let subject = message
+                .headers
+                .get_first_value("Subject")
+                .unwrap_or_default();
+            if subject == "Autocrypt Setup Message" && message.ctype.mimetype == "multipart/mixed" {
+                return Some(Verdict::AutocryptSetup);
+            }
+        }
+
+        envelope
+            .rcpt_to
+            .iter()
+            .all(|recipient| {
+                recipient_matches_passthrough(recipient, &self.config.passthrough_recipients)
+            })
+            .then_some(Verdict::Passthrough)
+    }
 }
 
 #[async_trait]
 impl SmtpHandler for OutgoingBeforeQueueHandler {
-    fn handle_mail(&self, address: &str) -> Result<(), String> {
-        log::debug!("handle_MAIL from {address}");
+    fn handle_mail(&self, address: &str, client: &ClientInfo) -> Result<(), SmtpReply> {
+        log::debug!("handle_MAIL from {address} sent by {client}");
 
         let parts: Vec<&str> = address.split('@').collect();
         if parts.len() != 2 {
-            return Err(format!("500 Invalid from address <{}>", address));
+            return Err(SmtpReply::new(
+                553,
+                (5, 1, 7),
+                format!("Invalid from address <{address}>"),
+            ));
         }
 
-        if let Err(e) = self.send_rate_limiter.check_key(&address.to_string()) {
+        // Limit the authenticated user if Postfix told us the SASL login.
+        let user = client.login.as_deref().unwrap_or(address).to_lowercase();
+        if let Err(e) = self.send_rate_limiter.check_key(&user) {
             // "<example@example.org> rate limited until: ..."
             log::debug!("<{address}> {e}");
-            return Err(format!("450 4.7.1: Too much mail from <{address}>, {e}"));
+            return Err(SmtpReply::new(
+                450,
+                (4, 7, 1),
+                format!("Too much mail from <{address}>, {e}"),
+            ));
         }
 
         // Cleanup
@@ -57,16 +117,24 @@ impl SmtpHandler for OutgoingBeforeQueueHandler {
         Ok(())
     }
 
-    fn check_data(&self, envelope: &Envelope) -> Result<(), String> {
-        log::debug!("Processing DATA message from {}", envelope.mail_from);
+    fn check_data(&self, envelope: &Envelope) -> Result<Option<Verdict>, SmtpReply> {
+        log::debug!(
+            "Processing DATA message from {} sent by {}",
+            envelope.mail_from,
+            envelope.client
+        );
 
         let message = match parse_mail(&envelope.data) {
             Ok(m) => m,
-            Err(e) => return Err(format!("500 Failed to parse message: {}", e)),
+            Err(e) => {
+                return Err(SmtpReply::new(
+                    554,
+                    (5, 6, 0),
+                    format!("Failed to parse message: {e}"),
+                ));
+            }
         };
 
-        let mail_encrypted = check_encrypted(&message, true);
-
         let from_header = message
             .headers
             .get_first_value("From")
@@ -74,85 +142,53 @@ impl SmtpHandler for OutgoingBeforeQueueHandler {
             .trim()
             .to_string();
 
-        let from_addr = extract_address(&from_header)
-            .ok_or(format!("500 Invalid FROM header: {from_header}"))?;
+        let from_addr = extract_address(&from_header).ok_or_else(|| {
+            SmtpReply::new(
+                554,
+                (5, 6, 0),
+                format!("Invalid FROM header: {from_header}"),
+            )
+        })?;
 
         if !envelope.mail_from.eq_ignore_ascii_case(&from_addr) {
-            return Err(format!(
-                "500 Invalid FROM <{}> for <{}>",
-                from_addr, envelope.mail_from
+            return Err(SmtpReply::new(
+                550,
+                (5, 7, 1),
+                format!("Invalid FROM <{from_addr}> for <{}>", envelope.mail_from),
             ));
         }
 
-        // Allow encrypted or securejoin messages
-        if mail_encrypted || is_securejoin(&message) {
-            log::info!("Outgoing: Filtering encrypted mail.");
-            return Ok(());
-        }
-
-        log::info!("Outgoing: Filtering unencrypted mail.");
-
-        // Allow passthrough senders
-        if self
-            .config
-            .passthrough_senders
-            .contains(&envelope.mail_from)
-        {
-            return Ok(());
-        }
-
-        // Allow self-sent Autocrypt Setup Message
-        if envelope.rcpt_to.len() == 1
-            && let Some(rcpt_to) = envelope.rcpt_to.first()
-            && *rcpt_to == envelope.mail_from
-        {
-            let subject = message
-                .headers
-                .get_first_value("Subject")
-                .unwrap_or_default();
-            if subject == "Autocrypt Setup Message" && message.ctype.mimetype == "multipart/mixed" {
-                return Ok(());
+        let verdict = self.verdict(&message, envelope);
+        match verdict {
+            Some(verdict) if verdict.is_encrypted() => {
+                log::info!("Outgoing: Filtering encrypted mail.");
+                Ok(Some(verdict))
             }
-        }
-
-        for recipient in &envelope.rcpt_to {
-            if !recipient_matches_passthrough(recipient, &self.config.passthrough_recipients) {
-                log::warn!("Rejected unencrypted mail from: {}", envelope.mail_from);
-                return Err(ENCRYPTION_NEEDED_523.to_string());
+            Some(verdict) => {
+                log::info!("Outgoing: Filtering unencrypted mail, verdict: {verdict}");
+                Ok(Some(verdict))
+            }
+            None => {
+                log::warn!(
+                    "Rejected unencrypted mail from: {} sent by {}",
+                    envelope.mail_from,
+                    envelope.client
+                );
+                Err(encryption_needed_523())
             }
         }
-
-        Ok(())
     }
 
-    async fn reinject_mail(&self, envelope: &Envelope) -> Result<(), String> {
+    async fn reinject_mail(
+        &self,
+        envelope: &Envelope,
+        delivery: Delivery,
+    ) -> Vec<Result<(), SmtpReply>> {
         log::debug!("Re-injecting the mail that passed checks");
-
-        let mailer = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("localhost")
-            .port(self.config.postfix_reinject_port)
-            .build();
-
-        let envelope_data = lettre::address::Envelope::new(
-            Some(
-                envelope
-                    .mail_from
-                    .parse()
-                    .map_err(|e| format!("Invalid from address: {}", e))?,
-            ),
-            envelope
-                .rcpt_to
-                .iter()
-                .map(|addr| addr.parse())
-                .collect::<Result<Vec<_>, _>>()
-                .map_err(|e| format!("Invalid to address: {}", e))?,
-        )
-        .map_err(|e| format!("Failed to create envelope: {}", e))?;
-
-        mailer
-            .send_raw(&envelope_data, &envelope.data)
-            .await
-            .map_err(format_smtp_error)?;
-
-        Ok(())
+        let Some(trace) = &self.trace else {
+            return self.reinjector.reinject(envelope, delivery).await;
+        };
+        let envelope = trace.stamp(envelope);
+        self.reinjector.reinject(&envelope, delivery).await
     }
 }
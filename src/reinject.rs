@@ -0,0 +1,1566 @@
+//! Reinjection of accepted messages into Postfix or another MTA.
+
+use crate::esmtp::{Body, RcptParameters, decode_xtext};
+use crate::reply::SmtpReply;
+use crate::smtp_server::Envelope;
+use crate::utils::{format_smtp_error, strip_final_crlf};
+use async_trait::async_trait;
+use lettre::transport::smtp::client::{AsyncSmtpConnection, AsyncTokioStream};
+use lettre::transport::smtp::commands::{Data, Noop, Quit, Rset};
+use lettre::transport::smtp::extension::{
+    ClientId, MailBodyParameter, MailParameter, RcptParameter,
+};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::process::Command;
+
+/// Time allowed for connecting and for every reply of the downstream server.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Awaits an I/O operation, failing with [`std::io::ErrorKind::TimedOut`] after [`TIMEOUT`].
+async fn timeout<T>(future: impl Future<Output = std::io::Result<T>>) -> std::io::Result<T> {
+    tokio::time::timeout(TIMEOUT, future)
+        .await
+        .map_err(|_| std::io::ErrorKind::TimedOut)?
+}
+
+/// Time after which idle connections are closed instead of reused.
+///
+/// Well below the default `smtpd_timeout` of 300 seconds after which Postfix drops idle clients.
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+/// Whether a message may be delivered to only some of its recipients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// A recipient rejected before the message is sent cancels the transaction,
+    /// as a single reply can't tell the client which recipients got the message.
+    ///
+    /// LMTP servers may still deliver to only some recipients after the message.
+    AllOrNothing,
+    /// The message is delivered to the recipients the server accepts.
+    PerRecipient,
+}
+
+/// Destination of the messages that passed the checks.
+#[async_trait]
+pub trait Reinjector: Send + Sync {
+    /// Delivers the message to the recipients of the envelope.
+    ///
+    /// Returns the outcome for every recipient in `envelope.rcpt_to`, in the same order,
+    /// with the reply to send back to the client if delivery failed.
+    async fn reinject(&self, envelope: &Envelope, delivery: Delivery)
+    -> Vec<Result<(), SmtpReply>>;
+}
+
+/// Fails the recipients that did not fail yet with `reply`.
+fn fail_remaining(outcomes: &mut [Result<(), SmtpReply>], reply: SmtpReply) {
+    for outcome in outcomes.iter_mut().filter(|outcome| outcome.is_ok()) {
+        *outcome = Err(reply.clone());
+    }
+}
+
+/// Whether the message is sent after the recipients got the outcomes they have so far.
+fn send_message(outcomes: &[Result<(), SmtpReply>], delivery: Delivery) -> bool {
+    match delivery {
+        Delivery::AllOrNothing => outcomes.iter().all(Result::is_ok),
+        Delivery::PerRecipient => outcomes.iter().any(Result::is_ok),
+    }
+}
+
+/// Address of an SMTP or LMTP server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// Host name or IP address and port.
+    Tcp {
+        /// Host name or IP address.
+        host: String,
+        /// TCP port.
+        port: u16,
+    },
+    /// Path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp { host, port } => write!(f, "{host}:{port}"),
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Endpoint {
+    /// Opens a connection to the server.
+    async fn connect(&self) -> Result<Box<dyn AsyncTokioStream>, SmtpReply> {
+        let connect = async {
+            Ok::<Box<dyn AsyncTokioStream>, std::io::Error>(match self {
+                Endpoint::Tcp { host, port } => {
+                    Box::new(TcpStream::connect((host.as_str(), *port)).await?)
+                }
+                Endpoint::Unix(path) => Box::new(UnixConnection(UnixStream::connect(path).await?)),
+            })
+        };
+        timeout(connect).await.map_err(|e| {
+            SmtpReply::new(451, (4, 4, 1), format!("Failed to connect to {self}: {e}"))
+        })
+    }
+}
+
+/// Unix domain socket connection usable by lettre, which is made for TCP.
+#[derive(Debug)]
+struct UnixConnection(UnixStream);
+
+impl AsyncTokioStream for UnixConnection {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl AsyncRead for UnixConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Idle connections to a downstream server, kept open for later transactions.
+///
+/// Shared by all sessions of a listener,
+/// connections beyond `size` are opened for a single transaction and then closed.
+struct Pool<C> {
+    idle: Mutex<Vec<(C, Instant)>>,
+    size: usize,
+}
+
+impl<C> Pool<C> {
+    fn new(size: usize) -> Self {
+        Self {
+            idle: Mutex::new(Vec::new()),
+            size,
+        }
+    }
+
+    /// Takes the most recently used connection and the time it has been idle.
+    fn take(&self) -> Option<(C, Duration)> {
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        idle.pop()
+            .map(|(connection, since)| (connection, since.elapsed()))
+    }
+
+    /// Keeps a connection for reuse, handing it back if the pool is full.
+    fn put(&self, connection: C) -> Result<(), C> {
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        if idle.len() >= self.size {
+            return Err(connection);
+        }
+        idle.push((connection, Instant::now()));
+        Ok(())
+    }
+}
+
+impl<C> fmt::Debug for Pool<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        f.debug_struct("Pool")
+            .field("idle", &idle.len())
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+/// Builds the `MAIL FROM` command with the reverse-path as received.
+///
+/// The path is passed on verbatim, as lettre's `Address` rejects some valid forms
+/// such as quoted local parts and IPv6 address literals.
+/// Bounces and other reports are reinjected with the null reverse-path.
+fn mail_command(envelope: &Envelope) -> String {
+    let mut command = format!("MAIL FROM:<{}>", envelope.mail_from);
+    for parameter in mail_parameters(envelope) {
+        command.push_str(&format!(" {parameter}"));
+    }
+    command + "\r\n"
+}
+
+/// Builds the `RCPT TO` command for a recipient of the envelope, with the forward-path as received.
+fn rcpt_command(envelope: &Envelope, index: usize, address: &str) -> String {
+    let mut command = format!("RCPT TO:<{address}>");
+    if let Some(parameters) = envelope.rcpt_parameters.get(index) {
+        for parameter in rcpt_parameters(parameters) {
+            command.push_str(&format!(" {parameter}"));
+        }
+    }
+    command + "\r\n"
+}
+
+/// Reinjects messages over SMTP, usually into a Postfix `smtpd` listening on localhost.
+///
+/// ESMTP parameters of the original transaction, including DSN requests,
+/// and the attributes of the original client are passed on
+/// so the downstream server handles the message as if it had received it directly.
+#[derive(Debug)]
+pub struct SmtpReinjector {
+    endpoint: Endpoint,
+    pool: Pool<AsyncSmtpConnection>,
+}
+
+impl SmtpReinjector {
+    /// Creates a reinjector for the SMTP server at `endpoint`
+    /// that opens a new connection for every message.
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            pool: Pool::new(0),
+        }
+    }
+
+    /// Keeps up to `size` connections open between messages.
+    pub fn pooled(mut self, size: usize) -> Self {
+        self.pool = Pool::new(size);
+        self
+    }
+
+    /// Takes an idle connection that still works or opens a new one.
+    async fn connection(&self) -> Result<AsyncSmtpConnection, SmtpReply> {
+        while let Some((mut connection, idle)) = self.pool.take() {
+            // The server may have closed the connection or answers NOOP with 421 when it does.
+            if idle < MAX_IDLE
+                && tokio::time::timeout(TIMEOUT, connection.test_connected())
+                    .await
+                    .unwrap_or_default()
+            {
+                return Ok(connection);
+            }
+            let _ = tokio::time::timeout(TIMEOUT, connection.abort()).await;
+        }
+
+        let stream = self.endpoint.connect().await?;
+        tokio::time::timeout(
+            TIMEOUT,
+            AsyncSmtpConnection::connect_with_transport(stream, &ClientId::default()),
+        )
+        .await
+        .map_err(|_| {
+            SmtpReply::new(
+                451,
+                (4, 4, 2),
+                format!("Timeout waiting for {}", self.endpoint),
+            )
+        })?
+        .map_err(format_smtp_error)
+    }
+
+    /// Returns a connection to the pool or closes it if the pool is full.
+    async fn release(&self, connection: AsyncSmtpConnection) {
+        if let Err(mut connection) = self.pool.put(connection) {
+            let _ = connection.command(Quit).await;
+        }
+    }
+
+    /// Resets the session and returns the connection to the pool, or closes it.
+    async fn reset(&self, mut connection: AsyncSmtpConnection) {
+        if self.pool.size > 0 && connection.command(Rset).await.is_ok() {
+            self.release(connection).await;
+        } else {
+            connection.abort().await;
+        }
+    }
+
+    /// Runs a transaction, storing the replies to rejected recipients in `outcomes`.
+    ///
+    /// Returns the reply for the remaining recipients if the message was not delivered.
+    async fn transaction(
+        &self,
+        envelope: &Envelope,
+        delivery: Delivery,
+        outcomes: &mut [Result<(), SmtpReply>],
+    ) -> Result<(), SmtpReply> {
+        let mut connection = self.connection().await?;
+
+        let result = async {
+            // Postfix rejects XFORWARD from hosts not in smtpd_authorized_xforward_hosts,
+            // the message is reinjected anyway.
+            for command in envelope.client.xforward_commands() {
+                if let Err(e) = connection.command(command).await {
+                    log::debug!("XFORWARD not accepted by the reinjection port: {e}");
+                    break;
+                }
+            }
+            connection.command(mail_command(envelope)).await?;
+            for ((index, to), outcome) in envelope.rcpt_to.iter().enumerate().zip(&mut *outcomes) {
+                match connection.command(rcpt_command(envelope, index, to)).await {
+                    Ok(_) => {}
+                    Err(e) if is_rejection(&e) => *outcome = Err(format_smtp_error(e)),
+                    Err(e) => return Err(e),
+                }
+            }
+            if !send_message(outcomes, delivery) {
+                return Ok(false);
+            }
+            connection.command(Data).await?;
+            connection.message(strip_final_crlf(&envelope.data)).await?;
+            Ok(true)
+        }
+        .await;
+
+        match result {
+            Ok(true) => {
+                self.release(connection).await;
+                Ok(())
+            }
+            Ok(false) => {
+                self.reset(connection).await;
+                outcomes.iter().cloned().collect()
+            }
+            Err(e) => {
+                // After a rejection the session is reset for the next message,
+                // 421 and I/O errors leave nothing to reuse.
+                match is_rejection(&e) {
+                    true => self.reset(connection).await,
+                    false => connection.abort().await,
+                }
+                Err(format_smtp_error(e))
+            }
+        }
+    }
+}
+
+/// Whether the server rejected a command and the session can go on,
+/// unlike after `421` or an I/O error.
+fn is_rejection(error: &lettre::transport::smtp::Error) -> bool {
+    error.status().is_some_and(|code| u16::from(code) != 421)
+}
+
+#[async_trait]
+impl Reinjector for SmtpReinjector {
+    async fn reinject(
+        &self,
+        envelope: &Envelope,
+        delivery: Delivery,
+    ) -> Vec<Result<(), SmtpReply>> {
+        let mut outcomes = vec![Ok(()); envelope.rcpt_to.len()];
+        if let Err(reply) = self.transaction(envelope, delivery, &mut outcomes).await {
+            fail_remaining(&mut outcomes, reply);
+        }
+        outcomes
+    }
+}
+
+/// Reinjects messages over LMTP (RFC 2033), e.g. directly into Dovecot.
+///
+/// The server replies for every recipient after the message,
+/// so it may be delivered to only some of them even with [`Delivery::AllOrNothing`].
+#[derive(Debug)]
+pub struct LmtpReinjector {
+    endpoint: Endpoint,
+    pool: Pool<LmtpConnection>,
+}
+
+impl LmtpReinjector {
+    /// Creates a reinjector for the LMTP server at `endpoint`
+    /// that opens a new connection for every message.
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            pool: Pool::new(0),
+        }
+    }
+
+    /// Keeps up to `size` connections open between messages.
+    pub fn pooled(mut self, size: usize) -> Self {
+        self.pool = Pool::new(size);
+        self
+    }
+
+    /// Takes an idle connection that still works or opens a new one.
+    async fn connection(&self) -> Result<LmtpConnection, SmtpReply> {
+        while let Some((mut connection, idle)) = self.pool.take() {
+            if idle < MAX_IDLE && connection.command(Noop).await.is_ok() {
+                return Ok(connection);
+            }
+            connection.quit().await;
+        }
+        LmtpConnection::connect(&self.endpoint).await
+    }
+
+    /// Runs a transaction, storing the replies to rejected recipients in `outcomes`.
+    ///
+    /// Returns the reply for the remaining recipients if the message was not sent.
+    async fn transaction(
+        &self,
+        envelope: &Envelope,
+        delivery: Delivery,
+        outcomes: &mut [Result<(), SmtpReply>],
+    ) -> Result<(), SmtpReply> {
+        let mut connection = self.connection().await?;
+
+        let result = async {
+            if connection.xforward {
+                for command in envelope.client.xforward_commands() {
+                    if let Err(e) = connection.command(command).await {
+                        log::debug!("XFORWARD not accepted by the LMTP server: {e}");
+                        break;
+                    }
+                }
+            }
+            connection.command(mail_command(envelope)).await?;
+            for ((index, to), outcome) in envelope.rcpt_to.iter().enumerate().zip(&mut *outcomes) {
+                match connection.command(rcpt_command(envelope, index, to)).await {
+                    Ok(_) => {}
+                    Err(reply) if !connection.broken && reply.code != 421 => *outcome = Err(reply),
+                    Err(reply) => return Err(reply),
+                }
+            }
+            if !send_message(outcomes, delivery) {
+                return Ok(false);
+            }
+            connection.command(Data).await?;
+            connection.message(&envelope.data).await?;
+
+            // There is a reply for every accepted recipient, all of them are read to stay in sync.
+            // Once the connection is broken, the rest fail the same way
+            // instead of each waiting for the timeout.
+            let mut lost: Option<SmtpReply> = None;
+            for outcome in outcomes.iter_mut().filter(|outcome| outcome.is_ok()) {
+                *outcome = match &lost {
+                    Some(reply) => Err(reply.clone()),
+                    None => connection.read_reply().await.map(drop),
+                };
+                if connection.broken {
+                    lost = outcome.clone().err();
+                }
+            }
+            Ok(true)
+        }
+        .await;
+
+        // Cancelled and failed transactions are reset,
+        // unless the server is closing the connection.
+        let reusable = !connection.broken
+            && match &result {
+                Ok(true) => true,
+                Ok(false) => self.pool.size > 0 && connection.command(Rset).await.is_ok(),
+                Err(reply) => {
+                    reply.code != 421
+                        && self.pool.size > 0
+                        && connection.command(Rset).await.is_ok()
+                }
+            };
+        if !reusable {
+            connection.quit().await;
+        } else if let Err(mut connection) = self.pool.put(connection) {
+            connection.quit().await;
+        }
+
+        match result {
+            Ok(true) => Ok(()),
+            Ok(false) => outcomes.iter().cloned().collect(),
+            Err(reply) => Err(reply),
+        }
+    }
+}
+
+#[async_trait]
+impl Reinjector for LmtpReinjector {
+    async fn reinject(
+        &self,
+        envelope: &Envelope,
+        delivery: Delivery,
+    ) -> Vec<Result<(), SmtpReply>> {
+        let mut outcomes = vec![Ok(()); envelope.rcpt_to.len()];
+        if let Err(reply) = self.transaction(envelope, delivery, &mut outcomes).await {
+            fail_remaining(&mut outcomes, reply);
+        }
+        outcomes
+    }
+}
+
+/// Client side of an LMTP session.
+///
+/// lettre only speaks SMTP, LMTP has a different greeting
+/// and a reply for every recipient after the message.
+struct LmtpConnection {
+    stream: BufReader<Box<dyn AsyncTokioStream>>,
+    /// Whether the server accepts `XFORWARD`.
+    xforward: bool,
+    /// Whether reading or writing failed, leaving the session out of sync.
+    broken: bool,
+}
+
+impl LmtpConnection {
+    /// Connects to the server and reads the greeting and the `LHLO` reply.
+    async fn connect(endpoint: &Endpoint) -> Result<Self, SmtpReply> {
+        let mut connection = Self {
+            stream: BufReader::new(endpoint.connect().await?),
+            xforward: false,
+            broken: false,
+        };
+        connection.read_reply().await?;
+        let lhlo = connection
+            .command(format!("LHLO {}\r\n", ClientId::default()))
+            .await?;
+        // Unlike Postfix, LMTP servers don't necessarily know XFORWARD.
+        connection.xforward = lhlo.lines.iter().any(|line| line.starts_with("XFORWARD"));
+        Ok(connection)
+    }
+
+    /// Ends the session unless the connection is already broken.
+    async fn quit(&mut self) {
+        if !self.broken {
+            let _ = self.command(Quit).await;
+        }
+    }
+
+    /// Sends a command, including CRLF, and reads the reply.
+    async fn command(&mut self, command: impl fmt::Display) -> Result<SmtpReply, SmtpReply> {
+        self.write(command.to_string().as_bytes()).await?;
+        self.read_reply().await
+    }
+
+    /// Sends the message content with the transparency dots added (RFC 5321, section 4.5.2)
+    /// and the terminating `<CRLF>.<CRLF>`.
+    async fn message(&mut self, data: &[u8]) -> Result<(), SmtpReply> {
+        let mut stuffed = Vec::with_capacity(data.len() + 5);
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if line.starts_with(b".") {
+                stuffed.push(b'.');
+            }
+            stuffed.extend_from_slice(line);
+        }
+        if !stuffed.is_empty() && !stuffed.ends_with(b"\r\n") {
+            stuffed.extend_from_slice(b"\r\n");
+        }
+        stuffed.extend_from_slice(b".\r\n");
+        self.write(&stuffed).await
+    }
+
+    /// Writes to the server.
+    async fn write(&mut self, data: &[u8]) -> Result<(), SmtpReply> {
+        let stream = self.stream.get_mut();
+        let write = async {
+            stream.write_all(data).await?;
+            stream.flush().await
+        };
+        let result = timeout(write).await.map_err(connection_lost);
+        self.broken |= result.is_err();
+        result
+    }
+
+    /// Reads a possibly multi-line reply, failing on `4xx` and `5xx` replies.
+    async fn read_reply(&mut self) -> Result<SmtpReply, SmtpReply> {
+        let (mut reply, mut last) = self.read_reply_line().await?;
+        while !last {
+            let (line, is_last) = self.read_reply_line().await?;
+            reply.lines.extend(line.lines);
+            last = is_last;
+        }
+        match reply.code {
+            200..400 => Ok(reply),
+            _ => Err(reply),
+        }
+    }
+
+    /// Reads a line of a reply, returning it and whether it is the last one.
+    async fn read_reply_line(&mut self) -> Result<(SmtpReply, bool), SmtpReply> {
+        let result = self.read_line().await;
+        self.broken |= result.is_err();
+        result
+    }
+
+    /// Reads and parses a line of a reply without tracking failures.
+    async fn read_line(&mut self) -> Result<(SmtpReply, bool), SmtpReply> {
+        let mut line = Vec::new();
+        let n = timeout(self.stream.read_until(b'\n', &mut line))
+            .await
+            .map_err(connection_lost)?;
+        if n == 0 {
+            return Err(connection_lost(std::io::ErrorKind::UnexpectedEof));
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (code, rest) = line
+            .split_at_checked(3)
+            .and_then(|(code, rest)| Some((code.parse().ok()?, rest)))
+            .ok_or_else(|| {
+                SmtpReply::new(
+                    451,
+                    (4, 5, 0),
+                    format!("Invalid reply from LMTP server: {line}"),
+                )
+            })?;
+        let (last, text) = match rest.strip_prefix('-') {
+            Some(text) => (false, text),
+            None => (true, rest.strip_prefix(' ').unwrap_or(rest)),
+        };
+        Ok((SmtpReply::from_response(code, text), last))
+    }
+}
+
+/// Reply for a connection to the downstream server that failed in the middle of a session.
+fn connection_lost(error: impl Into<std::io::Error>) -> SmtpReply {
+    SmtpReply::new(451, (4, 4, 2), format!("Connection lost: {}", error.into()))
+}
+
+/// Reinjects messages by piping them into a `sendmail`-compatible command.
+///
+/// DSN parameters are passed as command line options,
+/// `NOTIFY` only if all recipients requested the same notifications,
+/// `ORCPT` can't be passed at all.
+#[derive(Debug)]
+pub struct SendmailReinjector {
+    command: PathBuf,
+}
+
+impl SendmailReinjector {
+    /// Creates a reinjector running the `sendmail` program at `command`.
+    pub fn new(command: PathBuf) -> Self {
+        Self { command }
+    }
+
+    /// Runs the command, which takes the message for all recipients or none.
+    async fn send(&self, envelope: &Envelope) -> Result<(), SmtpReply> {
+        let parameters = &envelope.mail_parameters;
+        let mut command = Command::new(&self.command);
+        // A line with a single dot does not end the message.
+        command.arg("-i");
+        command.arg("-f").arg(match envelope.mail_from.as_str() {
+            "" => "<>",
+            mail_from => mail_from,
+        });
+        if let Some(ret) = parameters.ret {
+            command.arg("-R").arg(ret.to_string().to_lowercase());
+        }
+        if let Some(envid) = parameters.envid.as_deref().and_then(decode_xtext) {
+            command.arg("-V").arg(envid);
+        }
+        if let Some((first, others)) = envelope.rcpt_parameters.split_first()
+            && let Some(notify) = first.notify
+            && others
+                .iter()
+                .all(|parameters| parameters.notify == Some(notify))
+        {
+            command.arg("-N").arg(notify.to_string().to_lowercase());
+        }
+        command
+            .arg("--")
+            .args(&envelope.rcpt_to)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let failed = |e: &dyn fmt::Display| {
+            SmtpReply::new(
+                451,
+                (4, 3, 0),
+                format!("Failed to run {}: {e}", self.command.display()),
+            )
+        };
+        let mut child = command.spawn().map_err(|e| failed(&e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // Messages are submitted locally with LF line endings.
+            let mut data = Vec::with_capacity(envelope.data.len());
+            for line in envelope.data.split_inclusive(|&b| b == b'\n') {
+                match line.strip_suffix(b"\r\n") {
+                    Some(line) => {
+                        data.extend_from_slice(line);
+                        data.push(b'\n');
+                    }
+                    None => data.extend_from_slice(line),
+                }
+            }
+            // The exit status tells what went wrong if the command stops reading.
+            if let Err(e) = stdin.write_all(&data).await {
+                log::debug!("Failed to write message to {}: {e}", self.command.display());
+            }
+        }
+        let output = child.wait_with_output().await.map_err(|e| failed(&e))?;
+        if output.status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let mut text = format!("{} failed with {}", self.command.display(), output.status);
+        for line in stderr
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            text.push_str(": ");
+            text.push_str(line);
+        }
+        Err(exit_reply(output.status, text))
+    }
+}
+
+/// Reply for a `sendmail` command that failed, from its exit status (`sysexits.h`).
+///
+/// Only `EX_TEMPFAIL` and termination by a signal are transient.
+fn exit_reply(status: ExitStatus, text: String) -> SmtpReply {
+    match status.code() {
+        // EX_TEMPFAIL
+        None | Some(75) => SmtpReply::new(451, (4, 3, 0), text),
+        // EX_DATAERR
+        Some(65) => SmtpReply::new(554, (5, 6, 0), text),
+        // EX_NOUSER
+        Some(67) => SmtpReply::new(550, (5, 1, 1), text),
+        // EX_NOHOST
+        Some(68) => SmtpReply::new(550, (5, 1, 2), text),
+        // EX_NOPERM
+        Some(77) => SmtpReply::new(550, (5, 7, 1), text),
+        // EX_CONFIG
+        Some(78) => SmtpReply::new(554, (5, 3, 5), text),
+        Some(_) => SmtpReply::new(554, (5, 3, 0), text),
+    }
+}
+
+#[async_trait]
+impl Reinjector for SendmailReinjector {
+    async fn reinject(
+        &self,
+        envelope: &Envelope,
+        _delivery: Delivery,
+    ) -> Vec<Result<(), SmtpReply>> {
+        vec![self.send(envelope).await; envelope.rcpt_to.len()]
+    }
+}
+
+/// Keeps reinjected messages in memory instead of delivering them.
+///
+/// The mailboxes of recipients with a `full@` address are full.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryReinjector {
+    envelopes: std::sync::Mutex<Vec<Envelope>>,
+}
+
+#[cfg(test)]
+impl MemoryReinjector {
+    /// Returns the envelopes reinjected so far.
+    pub fn envelopes(&self) -> Vec<Envelope> {
+        self.envelopes.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Reinjector for MemoryReinjector {
+    async fn reinject(
+        &self,
+        envelope: &Envelope,
+        delivery: Delivery,
+    ) -> Vec<Result<(), SmtpReply>> {
+        let mut outcomes: Vec<_> = envelope
+            .rcpt_to
+            .iter()
+            .map(|address| match address.starts_with("full@") {
+                true => Err(SmtpReply::new(452, (4, 2, 2), "Mailbox full")),
+                false => Ok(()),
+            })
+            .collect();
+        if send_message(&outcomes, delivery) {
+            let delivered = envelope.with_recipients(outcomes.iter().map(Result::is_ok));
+            self.envelopes.lock().unwrap().push(delivered);
+        } else if let Err(reply) = outcomes.iter().cloned().collect::<Result<(), _>>() {
+            fail_remaining(&mut outcomes, reply);
+        }
+        outcomes
+    }
+}
+
+/// Shares a [`MemoryReinjector`] with a test checking the reinjected messages.
+#[cfg(test)]
+#[async_trait]
+impl Reinjector for std::sync::Arc<MemoryReinjector> {
+    async fn reinject(
+        &self,
+        envelope: &Envelope,
+        delivery: Delivery,
+    ) -> Vec<Result<(), SmtpReply>> {
+        self.as_ref().reinject(envelope, delivery).await
+    }
+}
+
+/// Builds the `MAIL FROM` parameters for reinjection.
+fn mail_parameters(envelope: &Envelope) -> Vec<MailParameter> {
+    let parameters = &envelope.mail_parameters;
+    let mut result = Vec::new();
+
+    if let Some(size) = parameters.size {
+        result.push(MailParameter::Size(size));
+    }
+
+    // Declare 8-bit content and non-ASCII addresses even if the client did not.
+    let body = match parameters.body {
+        Some(Body::SevenBit) if envelope.data.is_ascii() => Some(MailBodyParameter::SevenBit),
+        None if envelope.data.is_ascii() => None,
+        _ => Some(MailBodyParameter::EightBitMime),
+    };
+    if let Some(body) = body {
+        result.push(MailParameter::Body(body));
+    }
+
+    let non_ascii_addresses = !envelope.mail_from.is_ascii()
+        || envelope.rcpt_to.iter().any(|address| !address.is_ascii());
+    if parameters.smtputf8 || non_ascii_addresses {
+        result.push(MailParameter::SmtpUtfEight);
+    }
+
+    if let Some(ret) = parameters.ret {
+        result.push(MailParameter::Other {
+            keyword: "RET".to_string(),
+            value: Some(ret.to_string()),
+        });
+    }
+    // The value is stored as xtext and lettre encodes it again.
+    if let Some(envid) = parameters.envid.as_deref().and_then(decode_xtext) {
+        result.push(MailParameter::Other {
+            keyword: "ENVID".to_string(),
+            value: Some(envid),
+        });
+    }
+
+    result
+}
+
+/// Builds the `RCPT TO` parameters of a recipient for reinjection.
+fn rcpt_parameters(parameters: &RcptParameters) -> Vec<RcptParameter> {
+    let mut result = Vec::new();
+
+    if let Some(notify) = parameters.notify {
+        result.push(RcptParameter::Other {
+            keyword: "NOTIFY".to_string(),
+            value: Some(notify.to_string()),
+        });
+    }
+    // The value is stored as xtext and lettre encodes it again.
+    if let Some(orcpt) = parameters.orcpt.as_deref().and_then(decode_xtext) {
+        result.push(RcptParameter::Other {
+            keyword: "ORCPT".to_string(),
+            value: Some(orcpt),
+        });
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::esmtp::{MailParameters, Notify, Ret, parse_mail_from, parse_rcpt_to};
+    use crate::xclient::ClientInfo;
+    use rstest::*;
+    use std::os::unix::fs::PermissionsExt;
+    use testresult::TestResult;
+    use tokio::net::{TcpListener, UnixListener};
+    use tokio::task::JoinHandle;
+
+    type Server = JoinHandle<std::io::Result<Vec<String>>>;
+
+    /// Answers a single session and returns the commands and message content received.
+    ///
+    /// The command `verb` is answered with `reply`, other commands succeed
+    /// except `RCPT` for recipients with an `unknown@` address.
+    /// After `LHLO`, the message gets a reply for every recipient,
+    /// which fails for recipients with a `full@` address.
+    async fn record_commands<S>(
+        socket: S,
+        (verb, reply): (&'static str, &'static [u8]),
+    ) -> std::io::Result<Vec<String>>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let (reader, mut writer) = tokio::io::split(socket);
+        let mut reader = BufReader::new(reader);
+        writer.write_all(b"220 localhost ESMTP\r\n").await?;
+
+        let mut commands = Vec::new();
+        let mut recipients = Vec::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                break;
+            }
+            let command = line.trim_end().to_string();
+            let reply = match command.split(' ').next().unwrap_or_default() {
+                command_verb if command_verb == verb => reply.to_vec(),
+                "EHLO" => b"250-localhost\r\n250-DSN\r\n250 SMTPUTF8\r\n".to_vec(),
+                "LHLO" => b"250-localhost\r\n250 XFORWARD NAME ADDR HELO\r\n".to_vec(),
+                "RCPT" if command.contains("<unknown@") => {
+                    b"550 5.1.1 Recipient address rejected\r\n".to_vec()
+                }
+                "RCPT" => {
+                    recipients.push(command.clone());
+                    b"250 2.1.5 Ok\r\n".to_vec()
+                }
+                "DATA" => {
+                    writer.write_all(b"354 Go ahead\r\n").await?;
+                    let mut data = String::new();
+                    while reader.read_line(&mut data).await? > 0 && !data.ends_with("\r\n.\r\n") {}
+                    commands.push(command);
+                    commands.push(data);
+                    let lmtp = commands
+                        .first()
+                        .is_some_and(|lhlo| lhlo.starts_with("LHLO"));
+                    let reply = match lmtp {
+                        true => recipients
+                            .drain(..)
+                            .map(|rcpt| {
+                                if rcpt.contains("<full@") {
+                                    "452 4.2.2 Mailbox full\r\n"
+                                } else if rcpt.contains("<garbled@") {
+                                    "garbled\r\n"
+                                } else {
+                                    "250 2.0.0 Ok: delivered\r\n"
+                                }
+                            })
+                            .collect(),
+                        false => {
+                            recipients.clear();
+                            "250 2.0.0 Ok: queued\r\n".to_string()
+                        }
+                    };
+                    writer.write_all(reply.as_bytes()).await?;
+                    continue;
+                }
+                "QUIT" => b"221 2.0.0 Bye\r\n".to_vec(),
+                _ => b"250 2.0.0 Ok\r\n".to_vec(),
+            };
+            commands.push(command);
+            writer.write_all(&reply).await?;
+        }
+        Ok(commands)
+    }
+
+    /// Starts a server for a single session on a TCP port, see [`record_commands`].
+    async fn tcp_server(
+        override_reply: (&'static str, &'static [u8]),
+    ) -> TestResult<(Endpoint, Server)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = Endpoint::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr()?.port(),
+        };
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await?;
+            record_commands(socket, override_reply).await
+        });
+        Ok((endpoint, server))
+    }
+
+    /// Starts a server answering `sessions` sessions one after another on a TCP port.
+    async fn tcp_server_sessions(
+        override_reply: (&'static str, &'static [u8]),
+        sessions: usize,
+    ) -> TestResult<(Endpoint, JoinHandle<std::io::Result<Vec<Vec<String>>>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = Endpoint::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr()?.port(),
+        };
+        let server = tokio::spawn(async move {
+            let mut result = Vec::new();
+            for _ in 0..sessions {
+                let (socket, _) = listener.accept().await?;
+                result.push(record_commands(socket, override_reply).await?);
+            }
+            Ok(result)
+        });
+        Ok((endpoint, server))
+    }
+
+    /// Creates an SMTP or LMTP reinjector keeping one connection open.
+    fn pooled_reinjector(lmtp: bool, endpoint: Endpoint) -> Box<dyn Reinjector> {
+        match lmtp {
+            true => Box::new(LmtpReinjector::new(endpoint).pooled(1)),
+            false => Box::new(SmtpReinjector::new(endpoint).pooled(1)),
+        }
+    }
+
+    /// Creates an empty directory for the files of a test.
+    fn test_dir(name: &str) -> std::io::Result<PathBuf> {
+        let dir =
+            std::env::temp_dir().join(format!("filtermail-reinject-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    #[tokio::test]
+    async fn test_reinject_dsn_parameters() -> TestResult {
+        let (endpoint, server) = tcp_server(("NOOP", b"250 2.0.0 Ok\r\n")).await?;
+
+        let envelope = Envelope {
+            mail_from: "".to_string(),
+            rcpt_to: vec![
+                "bob@example.org".to_string(),
+                "carol@example.org".to_string(),
+            ],
+            mail_parameters: MailParameters {
+                ret: Some(Ret::Headers),
+                envid: Some("QQ+2B314+3D159".to_string()),
+                ..Default::default()
+            },
+            rcpt_parameters: vec![
+                RcptParameters {
+                    notify: Some(Notify {
+                        success: true,
+                        failure: true,
+                        delay: false,
+                    }),
+                    orcpt: Some("rfc822;Bob+2Btag+3Dx@example.org".to_string()),
+                },
+                RcptParameters::default(),
+            ],
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        let outcomes = SmtpReinjector::new(endpoint)
+            .reinject(&envelope, Delivery::AllOrNothing)
+            .await;
+        assert_eq!(outcomes, [Ok(()), Ok(())]);
+
+        let commands = server.await??;
+        assert_eq!(
+            commands[1..],
+            [
+                "MAIL FROM:<> RET=HDRS ENVID=QQ+2B314+3D159",
+                "RCPT TO:<bob@example.org> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;Bob+2Btag+3Dx@example.org",
+                "RCPT TO:<carol@example.org>",
+                "DATA",
+                "Subject: test\r\n\r\nbody\r\n.\r\n",
+                "QUIT",
+            ]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::accepted(b"250 2.0.0 Ok\r\n")]
+    #[case::not_authorized(b"550 5.7.0 Error: insufficient authorization\r\n")]
+    #[tokio::test]
+    async fn test_reinject_xforward(#[case] xforward_reply: &'static [u8]) -> TestResult {
+        let (endpoint, server) = tcp_server(("XFORWARD", xforward_reply)).await?;
+
+        let envelope = Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec!["bob@example.org".to_string()],
+            client: ClientInfo {
+                addr: Some("192.0.2.1".to_string()),
+                helo: Some("mail.example.net".to_string()),
+                ..Default::default()
+            },
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        let outcomes = SmtpReinjector::new(endpoint)
+            .reinject(&envelope, Delivery::AllOrNothing)
+            .await;
+        assert_eq!(outcomes, [Ok(())]);
+
+        let commands = server.await??;
+        assert_eq!(
+            commands[1..],
+            [
+                "XFORWARD ADDR=192.0.2.1 HELO=mail.example.net",
+                "MAIL FROM:<alice@example.org>",
+                "RCPT TO:<bob@example.org>",
+                "DATA",
+                "Subject: test\r\n\r\nbody\r\n.\r\n",
+                "QUIT",
+            ]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::enhanced(
+        b"550 5.1.1 <bob@example.org>: Recipient address rejected\r\n",
+        SmtpReply::new(550, (5, 1, 1), "<bob@example.org>: Recipient address rejected")
+    )]
+    #[case::no_enhanced(
+        b"554 Transaction failed\r\n",
+        SmtpReply::new(554, (5, 0, 0), "Transaction failed")
+    )]
+    #[case::transient(
+        b"452 4.3.1 Insufficient system storage\r\n",
+        SmtpReply::new(452, (4, 3, 1), "Insufficient system storage")
+    )]
+    #[tokio::test]
+    async fn test_reinject_rejected(
+        #[case] rcpt_reply: &'static [u8],
+        #[case] expected: SmtpReply,
+    ) -> TestResult {
+        let (endpoint, server) = tcp_server(("RCPT", rcpt_reply)).await?;
+
+        let envelope = Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec!["bob@example.org".to_string()],
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(
+            SmtpReinjector::new(endpoint)
+                .reinject(&envelope, Delivery::AllOrNothing)
+                .await,
+            [Err(expected)]
+        );
+
+        server.await??;
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::all_or_nothing(Delivery::AllOrNothing, &["QUIT"])]
+    #[case::per_recipient(
+        Delivery::PerRecipient,
+        &["DATA", "Subject: test\r\n\r\nbody\r\n.\r\n", "QUIT"]
+    )]
+    #[tokio::test]
+    async fn test_reinject_partially_rejected(
+        #[case] delivery: Delivery,
+        #[case] expected_commands: &[&str],
+    ) -> TestResult {
+        let (endpoint, server) = tcp_server(("NOOP", b"250 2.0.0 Ok\r\n")).await?;
+
+        let envelope = Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec![
+                "bob@example.org".to_string(),
+                "unknown@example.org".to_string(),
+            ],
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        let rejected = SmtpReply::new(550, (5, 1, 1), "Recipient address rejected");
+        let expected = match delivery {
+            Delivery::AllOrNothing => [Err(rejected.clone()), Err(rejected)],
+            Delivery::PerRecipient => [Ok(()), Err(rejected)],
+        };
+        assert_eq!(
+            SmtpReinjector::new(endpoint)
+                .reinject(&envelope, delivery)
+                .await,
+            expected
+        );
+
+        let commands = server.await??;
+        assert_eq!(commands[3], "RCPT TO:<unknown@example.org>");
+        assert_eq!(commands[4..], *expected_commands);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reinject_unix_socket() -> TestResult {
+        let path = test_dir("unix")?.join("smtpd.sock");
+        let listener = UnixListener::bind(&path)?;
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await?;
+            record_commands(socket, ("NOOP", b"250 2.0.0 Ok\r\n")).await
+        });
+
+        let envelope = Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec!["bob@example.org".to_string()],
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        let outcomes = SmtpReinjector::new(Endpoint::Unix(path.clone()))
+            .reinject(&envelope, Delivery::AllOrNothing)
+            .await;
+        assert_eq!(outcomes, [Ok(())]);
+
+        let commands = server.await??;
+        assert_eq!(commands[1], "MAIL FROM:<alice@example.org>");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reinject_connection_refused() -> TestResult {
+        let path = test_dir("refused")?.join("missing.sock");
+        let envelope = Envelope {
+            rcpt_to: vec!["bob@example.org".to_string()],
+            ..Default::default()
+        };
+        let outcomes = SmtpReinjector::new(Endpoint::Unix(path))
+            .reinject(&envelope, Delivery::AllOrNothing)
+            .await;
+        let [Err(reply)] = &outcomes[..] else {
+            panic!("unexpected outcomes: {outcomes:?}");
+        };
+        assert_eq!((reply.code, reply.enhanced), (451, Some((4, 4, 1).into())));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::delivered("carol@example.org", Ok(()))]
+    #[case::mailbox_full("full@example.org", Err(SmtpReply::new(452, (4, 2, 2), "Mailbox full")))]
+    #[tokio::test]
+    async fn test_reinject_lmtp(
+        #[case] recipient: &str,
+        #[case] expected: Result<(), SmtpReply>,
+    ) -> TestResult {
+        let (endpoint, server) = tcp_server(("NOOP", b"250 2.0.0 Ok\r\n")).await?;
+
+        let envelope = Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec!["bob@example.org".to_string(), recipient.to_string()],
+            rcpt_parameters: vec![RcptParameters::default(); 2],
+            client: ClientInfo {
+                addr: Some("192.0.2.1".to_string()),
+                ..Default::default()
+            },
+            data: b"Subject: test\r\n\r\n.hidden\r\n".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(
+            LmtpReinjector::new(endpoint)
+                .reinject(&envelope, Delivery::PerRecipient)
+                .await,
+            [Ok(()), expected]
+        );
+
+        let commands = server.await??;
+        assert_eq!(
+            commands[1..],
+            [
+                "XFORWARD ADDR=192.0.2.1",
+                "MAIL FROM:<alice@example.org>",
+                "RCPT TO:<bob@example.org>",
+                &format!("RCPT TO:<{recipient}>"),
+                "DATA",
+                "Subject: test\r\n\r\n..hidden\r\n.\r\n",
+                "QUIT",
+            ]
+        );
+        assert!(commands[0].starts_with("LHLO "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reinject_lmtp_broken_reply() -> TestResult {
+        let (endpoint, server) = tcp_server(("NOOP", b"250 2.0.0 Ok\r\n")).await?;
+
+        let envelope = Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec![
+                "bob@example.org".to_string(),
+                "garbled@example.org".to_string(),
+                "carol@example.org".to_string(),
+            ],
+            rcpt_parameters: vec![RcptParameters::default(); 3],
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        let outcomes = LmtpReinjector::new(endpoint)
+            .reinject(&envelope, Delivery::PerRecipient)
+            .await;
+        // The reply for Carol is not read, the connection is out of sync.
+        let [Ok(()), Err(garbled), Err(carol)] = &outcomes[..] else {
+            panic!("unexpected outcomes: {outcomes:?}");
+        };
+        assert_eq!(
+            (garbled.code, garbled.enhanced),
+            (451, Some((4, 5, 0).into()))
+        );
+        assert_eq!(carol, garbled);
+
+        let commands = server.await??;
+        assert_eq!(
+            commands.last().map(String::as_str),
+            Some("Subject: test\r\n\r\nbody\r\n.\r\n")
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::smtp(false)]
+    #[case::lmtp(true)]
+    #[tokio::test]
+    async fn test_reinject_raw_paths(#[case] lmtp: bool) -> TestResult {
+        let (endpoint, server) = tcp_server(("NOOP", b"250 2.0.0 Ok\r\n")).await?;
+
+        // Forms the parser accepts, but lettre's `Address` does not.
+        let mail_from = parse_mail_from("<\"john doe\"@example.org>").unwrap();
+        let rcpt_to = [
+            "<Postmaster>",
+            "<postmaster@[IPv6:2001:db8::1]> NOTIFY=NEVER",
+        ]
+        .map(|args| parse_rcpt_to(args, false).unwrap());
+        let envelope = Envelope {
+            mail_from: mail_from.address,
+            rcpt_to: rcpt_to.iter().map(|rcpt| rcpt.address.clone()).collect(),
+            rcpt_parameters: rcpt_to.iter().map(|rcpt| rcpt.parameters.clone()).collect(),
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        let reinjector: Box<dyn Reinjector> = match lmtp {
+            true => Box::new(LmtpReinjector::new(endpoint)),
+            false => Box::new(SmtpReinjector::new(endpoint)),
+        };
+        let outcomes = reinjector.reinject(&envelope, Delivery::AllOrNothing).await;
+        assert_eq!(outcomes, [Ok(()), Ok(())]);
+
+        let commands = server.await??;
+        assert_eq!(
+            commands[1..4],
+            [
+                "MAIL FROM:<\"john doe\"@example.org>",
+                "RCPT TO:<Postmaster>",
+                "RCPT TO:<postmaster@[ipv6:2001:db8::1]> NOTIFY=NEVER",
+            ]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::smtp(false)]
+    #[case::lmtp(true)]
+    #[tokio::test]
+    async fn test_reinject_pooled(#[case] lmtp: bool) -> TestResult {
+        // The server accepts a single connection, which is used for both messages.
+        let (endpoint, server) = tcp_server(("NOOP", b"250 2.0.0 Ok\r\n")).await?;
+        let reinjector = pooled_reinjector(lmtp, endpoint);
+
+        let envelope = Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec!["bob@example.org".to_string()],
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let outcomes = reinjector.reinject(&envelope, Delivery::AllOrNothing).await;
+            assert_eq!(outcomes, [Ok(())]);
+        }
+        drop(reinjector);
+
+        let commands = server.await??;
+        let transaction = [
+            "MAIL FROM:<alice@example.org>",
+            "RCPT TO:<bob@example.org>",
+            "DATA",
+            "Subject: test\r\n\r\nbody\r\n.\r\n",
+        ];
+        assert_eq!(commands[1..5], transaction);
+        assert_eq!(commands[5], "NOOP");
+        assert_eq!(commands[6..], transaction);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reinject_pool_reset() -> TestResult {
+        let (endpoint, server) =
+            tcp_server(("RCPT", b"550 5.1.1 Recipient address rejected\r\n")).await?;
+        let reinjector = pooled_reinjector(false, endpoint);
+
+        let envelope = Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec!["bob@example.org".to_string()],
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let outcomes = reinjector.reinject(&envelope, Delivery::AllOrNothing).await;
+            assert!(matches!(&outcomes[..], [Err(reply)] if reply.code == 550));
+        }
+        drop(reinjector);
+
+        let commands = server.await??;
+        assert_eq!(
+            commands[1..],
+            [
+                "MAIL FROM:<alice@example.org>",
+                "RCPT TO:<bob@example.org>",
+                "RSET",
+                "NOOP",
+                "MAIL FROM:<alice@example.org>",
+                "RCPT TO:<bob@example.org>",
+                "RSET",
+            ]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::smtp(false)]
+    #[case::lmtp(true)]
+    #[tokio::test]
+    async fn test_reinject_pool_reconnect(#[case] lmtp: bool) -> TestResult {
+        let (endpoint, server) =
+            tcp_server_sessions(("NOOP", b"421 4.4.2 Error: timeout exceeded\r\n"), 2).await?;
+        let reinjector = pooled_reinjector(lmtp, endpoint);
+
+        let envelope = Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec!["bob@example.org".to_string()],
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let outcomes = reinjector.reinject(&envelope, Delivery::AllOrNothing).await;
+            assert_eq!(outcomes, [Ok(())]);
+        }
+        drop(reinjector);
+
+        let sessions = server.await??;
+        assert_eq!(sessions[0][5..], ["NOOP", "QUIT"]);
+        assert_eq!(sessions[1][1], "MAIL FROM:<alice@example.org>");
+        assert_eq!(sessions[1].len(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reinject_sendmail() -> TestResult {
+        let command = test_dir("sendmail")?.join("sendmail");
+        std::fs::write(
+            &command,
+            "#!/bin/sh\n\
+             printf '%s\\n' \"$@\" > \"$0.args\"\n\
+             cat > \"$0.stdin\"\n\
+             case \"$*\" in *fail@*) echo 'sendmail: fatal: no queue' >&2; exit 75;; esac\n",
+        )?;
+        std::fs::set_permissions(&command, std::fs::Permissions::from_mode(0o755))?;
+        let reinjector = SendmailReinjector::new(command.clone());
+
+        let notify = Some(Notify {
+            success: false,
+            failure: true,
+            delay: false,
+        });
+        let mut envelope = Envelope {
+            mail_from: "".to_string(),
+            rcpt_to: vec![
+                "bob@example.org".to_string(),
+                "-carol@example.org".to_string(),
+            ],
+            mail_parameters: MailParameters {
+                ret: Some(Ret::Full),
+                envid: Some("a+2Bb".to_string()),
+                ..Default::default()
+            },
+            rcpt_parameters: vec![
+                RcptParameters {
+                    notify,
+                    orcpt: None,
+                };
+                2
+            ],
+            data: b"Subject: test\r\n\r\n.\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        let outcomes = reinjector.reinject(&envelope, Delivery::PerRecipient).await;
+        assert_eq!(outcomes, [Ok(()), Ok(())]);
+        assert_eq!(
+            std::fs::read_to_string(command.with_extension("args"))?,
+            "-i\n-f\n<>\n-R\nfull\n-V\na+b\n-N\nfailure\n--\nbob@example.org\n-carol@example.org\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(command.with_extension("stdin"))?,
+            "Subject: test\n\n.\nbody\n"
+        );
+
+        envelope.rcpt_to = vec!["fail@example.org".to_string()];
+        let outcomes = reinjector.reinject(&envelope, Delivery::PerRecipient).await;
+        let [Err(reply)] = &outcomes[..] else {
+            panic!("unexpected outcomes: {outcomes:?}");
+        };
+        assert_eq!((reply.code, reply.enhanced), (451, Some((4, 3, 0).into())));
+        assert!(
+            reply.lines[0].ends_with("sendmail: fatal: no queue"),
+            "{reply}"
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::tempfail(75, 451, (4, 3, 0))]
+    #[case::dataerr(65, 554, (5, 6, 0))]
+    #[case::nouser(67, 550, (5, 1, 1))]
+    #[case::noperm(77, 550, (5, 7, 1))]
+    #[case::software(70, 554, (5, 3, 0))]
+    #[tokio::test]
+    async fn test_reinject_sendmail_exit_status(
+        #[case] status: u8,
+        #[case] code: u16,
+        #[case] enhanced: (u8, u16, u16),
+    ) -> TestResult {
+        let command = test_dir(&format!("sendmail-{status}"))?.join("sendmail");
+        std::fs::write(
+            &command,
+            format!(
+                "#!/bin/sh
+                 cat > /dev/null
+                 echo 'sendmail: fatal: exiting' >&2
+                 exit {status}
+"
+            ),
+        )?;
+        std::fs::set_permissions(&command, std::fs::Permissions::from_mode(0o755))?;
+        let reinjector = SendmailReinjector::new(command);
+
+        let envelope = Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec!["bob@example.org".to_string()],
+            data: b"Subject: test\r\n\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        };
+        let outcomes = reinjector.reinject(&envelope, Delivery::AllOrNothing).await;
+        let [Err(reply)] = &outcomes[..] else {
+            panic!("unexpected outcomes: {outcomes:?}");
+        };
+        assert_eq!((reply.code, reply.enhanced), (code, Some(enhanced.into())));
+        assert!(
+            reply.lines[0].ends_with(&format!(
+                "failed with exit status: {status}: sendmail: fatal: exiting"
+            )),
+            "{reply}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_mail_parameters_8bit() {
+        let envelope = Envelope {
+            mail_from: "jörg@example.org".to_string(),
+            data: "Subject: Grüße\r\n\r\n".as_bytes().to_vec(),
+            ..Default::default()
+        };
+        let parameters: Vec<String> = mail_parameters(&envelope)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(parameters, ["BODY=8BITMIME", "SMTPUTF8"]);
+    }
+}
@@ -0,0 +1,186 @@
+//! SMTP replies with enhanced status codes.
+
+use std::fmt;
+
+/// Enhanced mail system status code (RFC 3463), such as `5.7.1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnhancedCode {
+    /// `2` for success, `4` for a transient and `5` for a permanent failure.
+    pub class: u8,
+    /// Category of the status, e.g. `1` for addressing or `7` for security or policy.
+    pub subject: u16,
+    /// Status within the subject.
+    pub detail: u16,
+}
+
+impl EnhancedCode {
+    /// Creates an enhanced status code from its three parts.
+    pub const fn new(class: u8, subject: u16, detail: u16) -> Self {
+        Self {
+            class,
+            subject,
+            detail,
+        }
+    }
+
+    /// Parses an enhanced status code such as `4.2.0`.
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('.');
+        let class = parts.next()?;
+        let subject = parts.next()?;
+        let detail = parts.next()?;
+        if parts.next().is_some() || class.len() != 1 || subject.len() > 3 || detail.len() > 3 {
+            return None;
+        }
+        Some(Self::new(
+            class.parse().ok()?,
+            subject.parse().ok()?,
+            detail.parse().ok()?,
+        ))
+    }
+}
+
+impl From<(u8, u16, u16)> for EnhancedCode {
+    fn from((class, subject, detail): (u8, u16, u16)) -> Self {
+        Self::new(class, subject, detail)
+    }
+}
+
+impl fmt::Display for EnhancedCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+/// A reply to an SMTP or LMTP command (RFC 5321, section 4.2).
+///
+/// Displayed without the final CRLF, with every line of a multi-line reply
+/// starting with the reply code and the enhanced status code (RFC 2034, section 3).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmtpReply {
+    /// Three-digit reply code.
+    pub code: u16,
+    /// Enhanced status code, `None` for the greeting, the HELO/EHLO reply and `354`.
+    pub enhanced: Option<EnhancedCode>,
+    /// Text of the reply, one entry per line.
+    pub lines: Vec<String>,
+}
+
+impl SmtpReply {
+    /// Creates a reply with an enhanced status code.
+    pub fn new(code: u16, enhanced: impl Into<EnhancedCode>, text: impl Into<String>) -> Self {
+        let enhanced = enhanced.into();
+        debug_assert_eq!(u16::from(enhanced.class), code / 100);
+        Self {
+            code,
+            enhanced: Some(enhanced),
+            lines: vec![text.into()],
+        }
+    }
+
+    /// Creates a reply without an enhanced status code.
+    pub fn plain(code: u16, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            enhanced: None,
+            lines: vec![text.into()],
+        }
+    }
+
+    /// Appends a line to the text of the reply.
+    pub fn line(mut self, text: impl Into<String>) -> Self {
+        self.lines.push(text.into());
+        self
+    }
+
+    /// The `250 2.0.0 OK` reply.
+    pub fn ok() -> Self {
+        Self::new(250, (2, 0, 0), "OK")
+    }
+
+    /// Whether the reply is a transient failure, so the command may succeed later.
+    pub fn is_transient(&self) -> bool {
+        self.code / 100 == 4
+    }
+
+    /// Builds a reply from the code and text received from another server.
+    ///
+    /// The enhanced status code is taken from the start of the text if it is there
+    /// and matches the class of the reply code,
+    /// otherwise it is `X.0.0` for `4xx` and `5xx` replies and `None` for others.
+    pub fn from_response(code: u16, text: &str) -> Self {
+        let class = u8::try_from(code / 100).unwrap_or_default();
+        if let Some((enhanced, rest)) = text.split_once(' ')
+            && let Some(enhanced) = EnhancedCode::parse(enhanced)
+            && enhanced.class == class
+        {
+            return Self::new(code, enhanced, rest);
+        }
+        match class {
+            4 | 5 => Self::new(code, (class, 0, 0), text),
+            _ => Self::plain(code, text),
+        }
+    }
+}
+
+impl fmt::Display for SmtpReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last = self.lines.len().saturating_sub(1);
+        for (index, line) in self.lines.iter().enumerate() {
+            if index > 0 {
+                f.write_str("\r\n")?;
+            }
+            let separator = if index == last { ' ' } else { '-' };
+            write!(f, "{}{separator}", self.code)?;
+            if let Some(enhanced) = self.enhanced {
+                write!(f, "{enhanced} ")?;
+            }
+            f.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            SmtpReply::new(450, (4, 7, 1), "Too much mail").to_string(),
+            "450 4.7.1 Too much mail"
+        );
+        assert_eq!(
+            SmtpReply::new(550, (5, 7, 1), "first")
+                .line("second")
+                .to_string(),
+            "550-5.7.1 first\r\n550 5.7.1 second"
+        );
+        assert_eq!(
+            SmtpReply::plain(250, "filtermail")
+                .line("SIZE 1024")
+                .line("SMTPUTF8")
+                .to_string(),
+            "250-filtermail\r\n250-SIZE 1024\r\n250 SMTPUTF8"
+        );
+    }
+
+    #[rstest]
+    #[case::enhanced(550, "5.7.1 Relay access denied", (5, 7, 1), "Relay access denied")]
+    #[case::no_enhanced(554, "Transaction failed", (5, 0, 0), "Transaction failed")]
+    #[case::wrong_class(451, "5.3.0 Local error", (4, 0, 0), "5.3.0 Local error")]
+    #[case::not_a_code(452, "4.x.1 Full", (4, 0, 0), "4.x.1 Full")]
+    fn test_from_response(
+        #[case] code: u16,
+        #[case] text: &str,
+        #[case] enhanced: (u8, u16, u16),
+        #[case] expected_text: &str,
+    ) {
+        let reply = SmtpReply::from_response(code, text);
+        assert_eq!(reply.code, code);
+        assert_eq!(reply.enhanced, Some(enhanced.into()));
+        assert_eq!(reply.lines, [expected_text]);
+    }
+}
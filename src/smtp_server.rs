@@ -1,215 +1,2345 @@
-//! A simplified SMTP server implementation for internal communication.
+//! A simplified SMTP and LMTP server implementation for internal communication.
 
-use crate::utils::extract_address;
+use crate::esmtp::{MailParameters, RcptParameters, parse_mail_from, parse_rcpt_to};
+use crate::listener::Listener;
+use crate::reinject::Delivery;
+use crate::reply::SmtpReply;
+use crate::trace::Verdict;
+use crate::xclient::{AuthorizedHosts, ClientInfo, XCLIENT_ATTRIBUTES, XFORWARD_ATTRIBUTES};
 use async_trait::async_trait;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    BufWriter,
+};
+use tokio::sync::{Semaphore, watch};
+use tokio::task::JoinSet;
 
 /// Represents an SMTP envelope with sender, recipients, and raw message data.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Envelope {
     pub mail_from: String,
     pub rcpt_to: Vec<String>,
+    /// Parameters of the MAIL FROM command.
+    pub mail_parameters: MailParameters,
+    /// Parameters of the RCPT TO commands, in the same order as `rcpt_to`.
+    pub rcpt_parameters: Vec<RcptParameters>,
+    /// Client that submitted the message to Postfix, as told by XCLIENT or XFORWARD.
+    pub client: ClientInfo,
     pub data: Vec<u8>,
+    /// Why the checks accepted the message, set before it is reinjected.
+    pub verdict: Option<Verdict>,
+}
+
+impl Envelope {
+    /// Copy of the envelope for the recipients selected by `keep`, in the order of `rcpt_to`.
+    pub fn with_recipients(&self, keep: impl IntoIterator<Item = bool>) -> Envelope {
+        let mut envelope = Envelope {
+            rcpt_to: Vec::new(),
+            rcpt_parameters: Vec::new(),
+            ..self.clone()
+        };
+        for ((index, address), keep) in self.rcpt_to.iter().enumerate().zip(keep) {
+            if keep {
+                envelope.rcpt_to.push(address.clone());
+                envelope
+                    .rcpt_parameters
+                    .push(self.rcpt_parameters.get(index).cloned().unwrap_or_default());
+            }
+        }
+        envelope
+    }
 }
 
 /// Trait defining the SMTP handler interface.
 #[async_trait]
 pub trait SmtpHandler: Send + Sync {
-    /// Handles the MAIL FROM command.
-    fn handle_mail(&self, address: &str) -> Result<(), String>;
+    /// Handles the MAIL FROM command of the given client.
+    fn handle_mail(&self, address: &str, client: &ClientInfo) -> Result<(), SmtpReply>;
 
-    /// Checks the DATA command before reinjection.
-    fn check_data(&self, envelope: &Envelope) -> Result<(), String>;
+    /// Handles the RCPT TO command for a recipient of the transaction in `envelope`.
+    ///
+    /// An error rejects only this recipient, the transaction continues with the others.
+    fn handle_rcpt(&self, _address: &str, _envelope: &Envelope) -> Result<(), SmtpReply> {
+        Ok(())
+    }
+
+    /// Checks the DATA command before reinjection, returning why the message is accepted.
+    fn check_data(&self, envelope: &Envelope) -> Result<Option<Verdict>, SmtpReply>;
 
     /// Reinjects the mail back to postfix.
-    async fn reinject_mail(&self, envelope: &Envelope) -> Result<(), String>;
+    ///
+    /// Returns the outcome for every recipient in `envelope.rcpt_to`, in the same order.
+    async fn reinject_mail(
+        &self,
+        envelope: &Envelope,
+        delivery: Delivery,
+    ) -> Vec<Result<(), SmtpReply>>;
+
+    /// Checks the message for every recipient before reinjection.
+    ///
+    /// Returns why the message is accepted and a verdict for each recipient
+    /// in `envelope.rcpt_to`, in the same order.
+    /// Defaults to the verdict of [`SmtpHandler::check_data`] for all recipients.
+    fn check_recipients(
+        &self,
+        envelope: &Envelope,
+    ) -> (Option<Verdict>, Vec<Result<(), SmtpReply>>) {
+        let recipients = envelope.rcpt_to.len();
+        match self.check_data(envelope) {
+            Ok(verdict) => (verdict, vec![Ok(()); recipients]),
+            Err(reply) => (None, vec![Err(reply); recipients]),
+        }
+    }
+
+    /// Handles the DATA command in LMTP mode, returning a reply for every recipient.
+    ///
+    /// The message is reinjected once for all recipients accepted by
+    /// [`SmtpHandler::check_recipients`], each of them gets the outcome of its delivery.
+    async fn handle_data_per_recipient(
+        &self,
+        envelope: &Envelope,
+    ) -> Vec<Result<SmtpReply, SmtpReply>> {
+        log::debug!("handle_DATA per recipient");
+        let (verdict, verdicts) = self.check_recipients(envelope);
+
+        let mut accepted = envelope.with_recipients(verdicts.iter().map(Result::is_ok));
+        accepted.verdict = verdict;
+        let mut reinjected = match accepted.rcpt_to.is_empty() {
+            true => Vec::new(),
+            false => self.reinject_mail(&accepted, Delivery::PerRecipient).await,
+        }
+        .into_iter();
+
+        envelope
+            .rcpt_to
+            .iter()
+            .zip(verdicts)
+            .map(|(address, verdict)| {
+                verdict?;
+                reinjected
+                    .next()
+                    .unwrap_or(Ok(()))
+                    .inspect_err(|e| log::warn!("Failed to reinject mail to {address}: {e}"))?;
+                Ok(SmtpReply::ok())
+            })
+            .collect()
+    }
 
     /// Handles the DATA command.
-    async fn handle_data(&self, envelope: &Envelope) -> Result<String, String> {
+    ///
+    /// The message is delivered to all recipients or none, as they share the reply.
+    async fn handle_data(&self, envelope: &mut Envelope) -> Result<SmtpReply, SmtpReply> {
         log::debug!("handle_DATA before-queue");
-        self.check_data(envelope)?;
-        self.reinject_mail(envelope).await.map_err(|e| {
-            log::warn!("Failed to reinject mail: {e}");
-            e
-        })?;
-        Ok("250 OK".to_string())
+        envelope.verdict = self.check_data(envelope)?;
+        self.reinject_mail(envelope, Delivery::AllOrNothing)
+            .await
+            .into_iter()
+            .collect::<Result<(), _>>()
+            .inspect_err(|e| log::warn!("Failed to reinject mail: {e}"))?;
+        Ok(SmtpReply::ok())
+    }
+}
+
+/// Timeouts for reading from the client and processing a message.
+///
+/// Defaults follow the minimum values from RFC 5321, section 4.5.3.2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time to wait for HELO/EHLO after the greeting.
+    pub greeting: Duration,
+    /// Time to wait for the MAIL command.
+    pub mail: Duration,
+    /// Time to wait for RCPT or DATA commands within a transaction.
+    pub rcpt: Duration,
+    /// Time to wait for the first line of the message after the `354` reply.
+    pub data_init: Duration,
+    /// Time to wait for every further line of the message or BDAT chunk.
+    pub data_block: Duration,
+    /// Time allowed for checking and reinjecting a complete message.
+    pub data_done: Duration,
+    /// Time to let open sessions finish after shutdown was requested.
+    pub shutdown: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            greeting: Duration::from_secs(5 * 60),
+            mail: Duration::from_secs(5 * 60),
+            rcpt: Duration::from_secs(5 * 60),
+            data_init: Duration::from_secs(2 * 60),
+            data_block: Duration::from_secs(3 * 60),
+            data_done: Duration::from_secs(10 * 60),
+            shutdown: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Awaits an I/O operation, failing with [`std::io::ErrorKind::TimedOut`] after `duration`.
+async fn timeout<T>(
+    duration: Duration,
+    future: impl Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    tokio::time::timeout(duration, future)
+        .await
+        .map_err(|_| std::io::ErrorKind::TimedOut)?
+}
+
+/// Waits until `true` is sent on the shutdown channel.
+///
+/// Never completes if the sender is dropped without requesting shutdown.
+pub async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    if shutdown.wait_for(|&stop| stop).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Protocol spoken by the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// SMTP (RFC 5321), with a single reply to the message for all recipients.
+    #[default]
+    Smtp,
+    /// LMTP (RFC 2033), with a reply to the message for every accepted recipient.
+    Lmtp,
+}
+
+impl Protocol {
+    /// Greeting sent when the session starts.
+    fn greeting(self) -> SmtpReply {
+        match self {
+            Protocol::Smtp => SmtpReply::plain(220, "filtermail SMTP"),
+            Protocol::Lmtp => SmtpReply::plain(220, "filtermail LMTP"),
+        }
     }
 }
 
-/// Runs the SMTP server on the specified address with the given handler and maximum message size.
+/// Limits on the work done concurrently by all sessions of a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of open SMTP sessions,
+    /// further connections are answered with `421` and closed.
+    pub max_sessions: usize,
+    /// Maximum number of messages checked and reinjected at the same time,
+    /// further complete messages wait for a slot.
+    pub max_transactions: usize,
+}
+
+/// Runs the SMTP server on the given listener with the handler and session settings.
+///
+/// A session is spawned for every accepted connection.
+/// Only peers in `xclient_hosts` may send `XCLIENT` and `XFORWARD`.
+/// Once shutdown is requested, no more connections are accepted
+/// and open sessions get up to [`Timeouts::shutdown`] to finish.
+#[allow(clippy::too_many_arguments)]
 pub async fn run_smtp_server<H>(
-    addr: &str,
+    listener: Listener,
     handler: Arc<H>,
+    protocol: Protocol,
     max_size: usize,
-) -> Result<(), Box<dyn std::error::Error>>
+    timeouts: Timeouts,
+    limits: Limits,
+    xclient_hosts: AuthorizedHosts,
+    mut shutdown: watch::Receiver<bool>,
+) -> std::io::Result<()>
 where
     H: SmtpHandler + 'static,
 {
-    let listener = TcpListener::bind(addr).await?;
+    let sessions = Arc::new(Semaphore::new(limits.max_sessions));
+    let transactions = Arc::new(Semaphore::new(limits.max_transactions));
+
     // message for backward compatibility with chatmaild tests.
     log::info!("entering serving loop");
 
+    let mut tasks = JoinSet::new();
     loop {
-        let (socket, _) = listener.accept().await?;
+        let (mut socket, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // Clean up finished sessions.
+            Some(_) = tasks.join_next() => continue,
+            () = shutdown_requested(&mut shutdown) => break,
+        };
 
-        // Disable Nagle's algorithm.
-        socket.set_nodelay(true)?;
+        let Ok(session) = sessions.clone().try_acquire_owned() else {
+            log::warn!(
+                "Rejecting connection, {} sessions are already open.",
+                limits.max_sessions
+            );
+            // Postfix retries the delivery later on a 421 reply.
+            tokio::spawn(async move {
+                let reply = SmtpReply::new(421, (4, 3, 2), "Too many connections, try again later");
+                if let Err(e) = socket.write_all(format!("{reply}\r\n").as_bytes()).await {
+                    log::debug!("Failed to reject connection: {e}");
+                }
+            });
+            continue;
+        };
 
         let handler = handler.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(socket, handler, max_size).await {
+        let transactions = transactions.clone();
+        let shutdown = shutdown.clone();
+        let xclient_allowed = xclient_hosts.allows(peer);
+        tasks.spawn(async move {
+            if let Err(e) = handle_connection(
+                socket,
+                xclient_allowed,
+                handler,
+                protocol,
+                max_size,
+                timeouts,
+                transactions,
+                shutdown,
+            )
+            .await
+            {
                 log::error!("Error handling connection: {e}");
             }
+            drop(session);
         });
     }
+
+    drop(listener);
+    let open = tasks.len();
+    log::info!(
+        "Shutting down, waiting up to {}s for {open} open sessions.",
+        timeouts.shutdown.as_secs()
+    );
+    let drain = async { while tasks.join_next().await.is_some() {} };
+    let _ = tokio::time::timeout(timeouts.shutdown, drain).await;
+
+    // Remaining sessions are aborted when the tasks are dropped.
+    let aborted = tasks.len();
+    log::info!(
+        "Shutdown complete: {} sessions drained, {aborted} aborted.",
+        open - aborted
+    );
+    Ok(())
+}
+
+/// Parses the arguments of `BDAT <chunk-size> [LAST]` (RFC 3030).
+///
+/// Returns the chunk size and whether this is the last chunk of the message.
+fn parse_bdat(cmd: &str) -> Option<(usize, bool)> {
+    let mut args = cmd.split_ascii_whitespace().skip(1);
+    let size = args.next()?.parse().ok()?;
+    let last = match args.next() {
+        None => false,
+        Some(arg) if arg.eq_ignore_ascii_case("LAST") => true,
+        Some(_) => return None,
+    };
+
+    if args.next().is_some() {
+        return None;
+    }
+
+    Some((size, last))
+}
+
+/// Maximum length of a command line including the CRLF (RFC 5321, section 4.5.3.1.4).
+const MAX_COMMAND_LINE: usize = 512;
+
+/// Maximum length of a MAIL or RCPT command line including the CRLF.
+///
+/// Extensions may make these longer: RCPT by up to 500 octets for the DSN parameters
+/// (RFC 3461, section 5), which is more than MAIL grows by for them,
+/// and both by 26 octets for the SIZE parameter (RFC 1870, section 3).
+const MAX_PARAMETERS_COMMAND_LINE: usize = MAX_COMMAND_LINE + 500 + 26;
+
+/// Maximum length of a text line including the CRLF (RFC 5321, section 4.5.3.1.6).
+const MAX_TEXT_LINE: usize = 1000;
+
+/// Result of reading a single line of input.
+#[derive(Debug, PartialEq)]
+enum LineRead {
+    /// A line terminated by LF was read.
+    Complete,
+    /// The line is longer than the limit and was discarded.
+    TooLong,
+    /// The connection was closed before the end of the line.
+    Eof,
+}
+
+/// Reads a line terminated by LF into `line`, storing at most `limit` octets.
+///
+/// The rest of a longer line is consumed and thrown away
+/// so a client can't make the buffer grow without bound.
+async fn read_line<R>(reader: &mut R, line: &mut Vec<u8>, limit: usize) -> std::io::Result<LineRead>
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+    let mut too_long = false;
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Ok(LineRead::Eof);
+        }
+
+        let (chunk, complete) = match buf.iter().position(|&b| b == b'\n') {
+            Some(end) => (buf.get(..=end).unwrap_or(buf), true),
+            None => (buf, false),
+        };
+        let consumed = chunk.len();
+
+        if !too_long {
+            if line.len() + consumed > limit {
+                too_long = true;
+                line.clear();
+            } else {
+                line.extend_from_slice(chunk);
+            }
+        }
+        reader.consume(consumed);
+
+        if complete {
+            return Ok(match too_long {
+                true => LineRead::TooLong,
+                false => LineRead::Complete,
+            });
+        }
+    }
+}
+
+/// Result of reading the message content following the DATA command.
+#[derive(Debug, PartialEq)]
+enum DataRead {
+    /// The complete message with the transparency dots removed.
+    Message(Vec<u8>),
+    /// The message exceeds the maximum size.
+    TooLarge,
+    /// A text line exceeds [`MAX_TEXT_LINE`], the rest of the message was discarded.
+    LineTooLong,
+    /// A line did not end with CRLF or the connection was closed.
+    Malformed,
+}
+
+/// Reads the message content up to the terminating `<CRLF>.<CRLF>`.
+///
+/// Content is handled as raw bytes, so 8-bit data does not need to be valid UTF-8.
+/// The leading dot that the client adds to lines starting with a dot
+/// is removed as described in RFC 5321, section 4.5.2.
+///
+/// Every line has to arrive within the data block timeout,
+/// except the first one which may take up to the data initiation timeout.
+///
+/// The size limit is checked after every line,
+/// so at most `max_size` plus one text line is held in memory.
+async fn read_data<R>(
+    reader: &mut R,
+    max_size: usize,
+    timeouts: &Timeouts,
+) -> std::io::Result<DataRead>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut data = Vec::new();
+    let mut line = Vec::new();
+    let mut line_too_long = false;
+    let mut line_timeout = timeouts.data_init;
+    loop {
+        let read = timeout(line_timeout, read_line(reader, &mut line, MAX_TEXT_LINE)).await?;
+        line_timeout = timeouts.data_block;
+        match read {
+            LineRead::Complete => {}
+            LineRead::TooLong => {
+                // Keep reading up to the end of the message to stay in sync with the client.
+                log::warn!("DATA line exceeds {MAX_TEXT_LINE} octets.");
+                line_too_long = true;
+                data = Vec::new();
+                continue;
+            }
+            LineRead::Eof => {
+                log::warn!("Connection closed during DATA! Closing connection.");
+                return Ok(DataRead::Malformed);
+            }
+        }
+
+        if line == b".\r\n" {
+            return Ok(match line_too_long {
+                true => DataRead::LineTooLong,
+                false => DataRead::Message(data),
+            });
+        }
+
+        if !line.ends_with(b"\r\n") {
+            log::warn!("Malformed DATA line without CRLF ending! Closing connection.");
+            return Ok(DataRead::Malformed);
+        }
+
+        if line_too_long {
+            continue;
+        }
+
+        let unstuffed = line.strip_prefix(b".").unwrap_or(&line);
+        data.extend_from_slice(unstuffed);
+
+        if data.len() > max_size {
+            return Ok(DataRead::TooLarge);
+        }
+    }
+}
+
+/// State of an SMTP session.
+///
+/// Commands sent out of this order are rejected with `503`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionState {
+    /// Waiting for HELO or EHLO.
+    Connected,
+    /// No mail transaction in progress.
+    Greeted,
+    /// MAIL FROM accepted, waiting for recipients.
+    Mail,
+    /// At least one recipient accepted.
+    Rcpt,
+    /// Receiving the message in BDAT chunks.
+    Bdat,
+}
+
+/// Queues a reply to the client.
+///
+/// Replies are not flushed here to support pipelining (RFC 2920),
+/// the session loop sends them once there is no more buffered input to process.
+async fn write_reply<W>(writer: &mut W, reply: &SmtpReply) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let reply = reply.to_string();
+    log::debug!("Sent: {reply}");
+    writer.write_all(reply.as_bytes()).await?;
+    writer.write_all(b"\r\n").await
+}
+
+/// Passes the complete message to the handler and resets the envelope for the next transaction.
+///
+/// With LMTP, a reply is sent for every recipient.
+async fn finish_transaction<H, W>(
+    handler: &H,
+    protocol: Protocol,
+    envelope: &mut Envelope,
+    writer: &mut W,
+    data_done: Duration,
+    transactions: &Semaphore,
+) -> std::io::Result<()>
+where
+    H: SmtpHandler + ?Sized,
+    W: AsyncWrite + Unpin,
+{
+    // Waiting for a free slot counts towards the timeout as well.
+    let result = timeout(data_done, async {
+        let _permit = transactions
+            .acquire()
+            .await
+            .map_err(std::io::Error::other)?;
+        Ok(match protocol {
+            Protocol::Smtp => vec![handler.handle_data(envelope).await],
+            Protocol::Lmtp => handler.handle_data_per_recipient(envelope).await,
+        })
+    })
+    .await?;
+    for reply in result {
+        match reply {
+            Ok(reply) | Err(reply) => write_reply(writer, &reply).await?,
+        }
+    }
+
+    *envelope = Envelope::default();
+    Ok(())
 }
 
 /// Handles an individual SMTP connection.
-async fn handle_connection<H>(
-    socket: TcpStream,
+///
+/// `XCLIENT` and `XFORWARD` are only accepted if `xclient_allowed` is set.
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection<S, H>(
+    socket: S,
+    xclient_allowed: bool,
     handler: Arc<H>,
+    protocol: Protocol,
     max_size: usize,
+    timeouts: Timeouts,
+    transactions: Arc<Semaphore>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<(), Box<dyn std::error::Error>>
 where
+    S: AsyncRead + AsyncWrite,
     H: SmtpHandler,
 {
-    let (reader, writer) = socket.into_split();
+    let (reader, writer) = tokio::io::split(socket);
     let mut reader = BufReader::new(reader);
     let mut writer = BufWriter::new(writer);
-    let mut line = String::new();
 
-    writer.write_all(b"220 filtermail SMTP\r\n").await?;
-    writer.flush().await?;
+    match serve_session(
+        &mut reader,
+        &mut writer,
+        xclient_allowed,
+        handler.as_ref(),
+        protocol,
+        max_size,
+        &timeouts,
+        &transactions,
+        &mut shutdown,
+    )
+    .await
+    {
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+            log::warn!("SMTP session timed out, closing connection.");
+            write_reply(
+                &mut writer,
+                &SmtpReply::new(421, (4, 4, 2), "filtermail Error: timeout exceeded"),
+            )
+            .await?;
+            writer.flush().await?;
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
 
-    let mut envelope = Envelope {
-        mail_from: String::new(),
-        rcpt_to: Vec::new(),
-        data: Vec::new(),
-    };
+/// Runs the SMTP dialogue until the client quits or the connection is closed.
+///
+/// After shutdown is requested, a transaction in progress is still completed,
+/// but the session is closed with `421` as soon as it is idle.
+#[allow(clippy::too_many_arguments)]
+async fn serve_session<R, W, H>(
+    reader: &mut BufReader<R>,
+    writer: &mut BufWriter<W>,
+    xclient_allowed: bool,
+    handler: &H,
+    protocol: Protocol,
+    max_size: usize,
+    timeouts: &Timeouts,
+    transactions: &Semaphore,
+    shutdown: &mut watch::Receiver<bool>,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    H: SmtpHandler + ?Sized,
+{
+    let mut line = Vec::new();
+
+    write_reply(writer, &protocol.greeting()).await?;
+
+    let mut envelope = Envelope::default();
+    let mut state = SessionState::Connected;
+    // XCLIENT and XFORWARD attributes apply to all following transactions.
+    let mut client = ClientInfo::default();
 
     'connection: loop {
-        line.clear();
-        let n = reader.read_line(&mut line).await?;
-        if n == 0 {
-            break 'connection;
+        // Send the replies to a pipelined command group at once
+        // only after all commands the client has sent so far are processed.
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+
+        let command_timeout = match state {
+            SessionState::Connected => timeouts.greeting,
+            SessionState::Greeted => timeouts.mail,
+            SessionState::Mail | SessionState::Rcpt => timeouts.rcpt,
+            SessionState::Bdat => timeouts.data_block,
+        };
+        let idle = matches!(state, SessionState::Connected | SessionState::Greeted);
+        let read = tokio::select! {
+            biased;
+            () = shutdown_requested(shutdown), if idle => {
+                write_reply(writer, &SmtpReply::new(421, (4, 3, 2), "filtermail shutting down, try again later")).await?;
+                break 'connection;
+            }
+            read = timeout(command_timeout, read_line(reader, &mut line, MAX_PARAMETERS_COMMAND_LINE)) => read?,
+        };
+        match read {
+            LineRead::Complete => {}
+            LineRead::TooLong => {
+                log::warn!("Command line exceeds {MAX_PARAMETERS_COMMAND_LINE} octets.");
+                write_reply(writer, &SmtpReply::new(500, (5, 5, 2), "Line too long")).await?;
+                continue 'connection;
+            }
+            LineRead::Eof => break 'connection,
         }
 
         // Remove CRLF
         // Note: this will kill the connection if any line doesn't end with CRLF.
         // This is intentional as stray LF most likely means an attempt to exploit the server.
-        let Some(cmd) = line.strip_suffix("\r\n") else {
+        let Some(cmd) = line.strip_suffix(b"\r\n") else {
             log::warn!("Malformed command without CRLF ending! Closing connection.");
             break 'connection;
         };
 
+        let Ok(cmd) = std::str::from_utf8(cmd) else {
+            log::warn!("Received command that is not valid UTF-8.");
+            write_reply(
+                writer,
+                &SmtpReply::new(500, (5, 5, 2), "Invalid command encoding"),
+            )
+            .await?;
+            continue 'connection;
+        };
+
         log::debug!("Received: {cmd}");
 
-        if cmd.to_uppercase().starts_with("HELO") || cmd.to_uppercase().starts_with("EHLO") {
-            writer.write_all(b"250 OK\r\n").await?;
-            writer.flush().await?;
-        } else if cmd.to_uppercase().starts_with("MAIL FROM:") {
-            if let Some(from) = extract_address(cmd) {
-                match handler.handle_mail(&from) {
+        let (verb, args) = cmd.split_once(' ').unwrap_or((cmd, ""));
+        let verb_upper = verb.to_ascii_uppercase();
+
+        let limit = match verb_upper.as_str() {
+            "MAIL" | "RCPT" => MAX_PARAMETERS_COMMAND_LINE,
+            _ => MAX_COMMAND_LINE,
+        };
+        if line.len() > limit {
+            log::warn!("Command line exceeds {limit} octets.");
+            write_reply(writer, &SmtpReply::new(500, (5, 5, 2), "Line too long")).await?;
+            continue 'connection;
+        }
+
+        match verb_upper.as_str() {
+            // LMTP replaces HELO and EHLO with LHLO (RFC 2033, section 4.1).
+            "HELO" | "EHLO" if protocol == Protocol::Lmtp => {
+                write_reply(
+                    writer,
+                    &SmtpReply::new(500, (5, 5, 1), "Command not recognized"),
+                )
+                .await?;
+            }
+            "LHLO" if protocol == Protocol::Smtp => {
+                write_reply(
+                    writer,
+                    &SmtpReply::new(500, (5, 5, 1), "Command not recognized"),
+                )
+                .await?;
+            }
+            "HELO" | "EHLO" if args.trim().is_empty() => {
+                write_reply(
+                    writer,
+                    &SmtpReply::new(501, (5, 5, 4), "Syntax: EHLO hostname"),
+                )
+                .await?;
+            }
+            "LHLO" if args.trim().is_empty() => {
+                write_reply(
+                    writer,
+                    &SmtpReply::new(501, (5, 5, 4), "Syntax: LHLO hostname"),
+                )
+                .await?;
+            }
+            "HELO" => {
+                envelope = Envelope::default();
+                state = SessionState::Greeted;
+                write_reply(writer, &SmtpReply::plain(250, "filtermail")).await?;
+            }
+            "EHLO" | "LHLO" => {
+                envelope = Envelope::default();
+                state = SessionState::Greeted;
+                let mut reply = SmtpReply::plain(250, "filtermail")
+                    .line(format!("SIZE {max_size}"))
+                    .line("8BITMIME")
+                    .line("PIPELINING")
+                    .line("CHUNKING")
+                    .line("ENHANCEDSTATUSCODES")
+                    .line("DSN");
+                if xclient_allowed {
+                    reply = reply
+                        .line(format!("XCLIENT {XCLIENT_ATTRIBUTES}"))
+                        .line(format!("XFORWARD {XFORWARD_ATTRIBUTES}"));
+                }
+                let reply = reply.line("SMTPUTF8");
+                write_reply(writer, &reply).await?;
+            }
+            "MAIL" => {
+                let bad_sequence = match state {
+                    SessionState::Connected => {
+                        Some(SmtpReply::new(503, (5, 5, 1), "Send HELO/EHLO first"))
+                    }
+                    SessionState::Greeted => None,
+                    SessionState::Mail | SessionState::Rcpt | SessionState::Bdat => {
+                        Some(SmtpReply::new(503, (5, 5, 1), "Nested MAIL command"))
+                    }
+                };
+                if let Some(reply) = bad_sequence {
+                    write_reply(writer, &reply).await?;
+                    continue 'connection;
+                }
+
+                if !args.to_ascii_uppercase().starts_with("FROM:") {
+                    write_reply(
+                        writer,
+                        &SmtpReply::new(501, (5, 5, 4), "Syntax: MAIL FROM:<address>"),
+                    )
+                    .await?;
+                    continue 'connection;
+                }
+
+                let mail_from = match parse_mail_from(args.get("FROM:".len()..).unwrap_or_default())
+                {
+                    Ok(mail_from) => mail_from,
+                    Err(e) => {
+                        log::warn!("Rejected MAIL FROM command: {e}. Received: {cmd}");
+                        write_reply(writer, &e).await?;
+                        continue 'connection;
+                    }
+                };
+
+                if mail_from
+                    .parameters
+                    .size
+                    .is_some_and(|size| size > max_size)
+                {
+                    write_reply(
+                        writer,
+                        &SmtpReply::new(
+                            552,
+                            (5, 3, 4),
+                            "Message size exceeds fixed maximum message size",
+                        ),
+                    )
+                    .await?;
+                    continue 'connection;
+                }
+
+                match handler.handle_mail(&mail_from.address, &client) {
                     Ok(_) => {
-                        envelope.mail_from = from;
-                        writer.write_all(b"250 OK\r\n").await?;
-                        writer.flush().await?;
+                        envelope.client = client.clone();
+                        envelope.mail_from = mail_from.address;
+                        envelope.mail_parameters = mail_from.parameters;
+                        state = SessionState::Mail;
+                        write_reply(writer, &SmtpReply::new(250, (2, 1, 0), "OK")).await?;
                     }
                     Err(e) => {
-                        writer.write_all(format!("{}\r\n", e).as_bytes()).await?;
-                        writer.flush().await?;
+                        write_reply(writer, &e).await?;
                         break 'connection;
                     }
                 }
-            } else {
-                log::warn!("Invalid MAIL FROM command. Can't extract address. Received: {cmd}");
-                writer
-                    .write_all(b"500 Invalid address in MAIL FROM\r\n")
+            }
+            "RCPT" => {
+                let bad_sequence = match state {
+                    SessionState::Connected | SessionState::Greeted => {
+                        Some(SmtpReply::new(503, (5, 5, 1), "Need MAIL before RCPT"))
+                    }
+                    SessionState::Mail | SessionState::Rcpt => None,
+                    SessionState::Bdat => Some(SmtpReply::new(
+                        503,
+                        (5, 5, 1),
+                        "RCPT not allowed after BDAT",
+                    )),
+                };
+                if let Some(reply) = bad_sequence {
+                    write_reply(writer, &reply).await?;
+                    continue 'connection;
+                }
+
+                if !args.to_ascii_uppercase().starts_with("TO:") {
+                    write_reply(
+                        writer,
+                        &SmtpReply::new(501, (5, 5, 4), "Syntax: RCPT TO:<address>"),
+                    )
                     .await?;
-                writer.flush().await?;
+                    continue 'connection;
+                }
+
+                let rcpt_to = match parse_rcpt_to(
+                    args.get("TO:".len()..).unwrap_or_default(),
+                    envelope.mail_parameters.smtputf8,
+                ) {
+                    Ok(rcpt_to) => rcpt_to,
+                    Err(e) => {
+                        log::warn!("Rejected RCPT TO command: {e}. Received: {cmd}");
+                        write_reply(writer, &e).await?;
+                        continue 'connection;
+                    }
+                };
+
+                if let Err(e) = handler.handle_rcpt(&rcpt_to.address, &envelope) {
+                    log::info!("Rejected recipient <{}>: {e}", rcpt_to.address);
+                    write_reply(writer, &e).await?;
+                    continue 'connection;
+                }
+
+                envelope.rcpt_to.push(rcpt_to.address);
+                envelope.rcpt_parameters.push(rcpt_to.parameters);
+                state = SessionState::Rcpt;
+                write_reply(writer, &SmtpReply::new(250, (2, 1, 5), "OK")).await?;
             }
-        } else if cmd.to_uppercase().starts_with("RCPT TO:") {
-            if let Some(to) = extract_address(cmd) {
-                envelope.rcpt_to.push(to);
-                writer.write_all(b"250 OK\r\n").await?;
+            "DATA" => {
+                if !args.is_empty() {
+                    write_reply(writer, &SmtpReply::new(501, (5, 5, 4), "Syntax: DATA")).await?;
+                    continue 'connection;
+                }
+
+                let bad_sequence = match state {
+                    SessionState::Connected | SessionState::Greeted => {
+                        Some(SmtpReply::new(503, (5, 5, 1), "Need MAIL before DATA"))
+                    }
+                    SessionState::Mail => {
+                        Some(SmtpReply::new(503, (5, 5, 1), "Need RCPT before DATA"))
+                    }
+                    SessionState::Rcpt => None,
+                    SessionState::Bdat => Some(SmtpReply::new(
+                        503,
+                        (5, 5, 1),
+                        "DATA not allowed after BDAT",
+                    )),
+                };
+                if let Some(reply) = bad_sequence {
+                    write_reply(writer, &reply).await?;
+                    continue 'connection;
+                }
+
+                // DATA is a synchronisation point, the client waits for this reply
+                // before sending the message.
+                write_reply(
+                    writer,
+                    &SmtpReply::plain(354, "End data with <CR><LF>.<CR><LF>"),
+                )
+                .await?;
                 writer.flush().await?;
-            }
-        } else if cmd.to_uppercase().starts_with("DATA") {
-            writer
-                .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                match read_data(reader, max_size, timeouts).await? {
+                    DataRead::Message(data) => envelope.data = data,
+                    DataRead::TooLarge => {
+                        write_reply(
+                            writer,
+                            &SmtpReply::new(552, (5, 3, 4), "Message exceeds maximum size"),
+                        )
+                        .await?;
+                        break 'connection;
+                    }
+                    DataRead::LineTooLong => {
+                        write_reply(writer, &SmtpReply::new(500, (5, 5, 2), "Line too long"))
+                            .await?;
+                        envelope = Envelope::default();
+                        state = SessionState::Greeted;
+                        continue 'connection;
+                    }
+                    DataRead::Malformed => break 'connection,
+                }
+
+                finish_transaction(
+                    handler,
+                    protocol,
+                    &mut envelope,
+                    writer,
+                    timeouts.data_done,
+                    transactions,
+                )
                 .await?;
-            writer.flush().await?;
-            let mut data = Vec::new();
-            let mut data_line = String::new();
-            'data_read: loop {
-                data_line.clear();
-                reader.read_line(&mut data_line).await?;
+                state = SessionState::Greeted;
+            }
+            "BDAT" => {
+                // Without the size, the chunk the client sends anyway can't be skipped
+                // and would be read as commands.
+                let Some((size, last)) = parse_bdat(cmd) else {
+                    write_reply(
+                        writer,
+                        &SmtpReply::new(501, (5, 5, 4), "Syntax: BDAT <size> [LAST]"),
+                    )
+                    .await?;
+                    break 'connection;
+                };
 
-                if data_line == ".\r\n" {
-                    break 'data_read;
+                let bad_sequence = match state {
+                    SessionState::Connected | SessionState::Greeted => {
+                        Some(SmtpReply::new(503, (5, 5, 1), "Need MAIL before BDAT"))
+                    }
+                    SessionState::Mail => {
+                        Some(SmtpReply::new(503, (5, 5, 1), "Need RCPT before BDAT"))
+                    }
+                    SessionState::Rcpt | SessionState::Bdat => None,
+                };
+                if let Some(reply) = bad_sequence {
+                    // The client sends the chunk regardless of the reply,
+                    // skip it to stay in sync.
+                    let (mut chunk, mut sink) = (reader.take(size as u64), tokio::io::sink());
+                    let skip = tokio::io::copy(&mut chunk, &mut sink);
+                    timeout(timeouts.data_block, skip).await?;
+                    write_reply(writer, &reply).await?;
+                    continue 'connection;
                 }
 
-                if !data_line.ends_with("\r\n") {
-                    log::warn!("Malformed DATA line without CRLF ending! Closing connection.");
+                // Chunks are not read at all once the message is too large,
+                // so the connection can't be kept in sync with the client.
+                if envelope.data.len().saturating_add(size) > max_size {
+                    write_reply(
+                        writer,
+                        &SmtpReply::new(552, (5, 3, 4), "Message exceeds maximum size"),
+                    )
+                    .await?;
                     break 'connection;
                 }
 
-                data.extend_from_slice(data_line.as_bytes());
-
-                if data.len() > max_size {
-                    writer
-                        .write_all(b"552 Message exceeds maximum size\r\n")
-                        .await?;
-                    writer.flush().await?;
+                let mut chunk = reader.take(size as u64);
+                let n = timeout(timeouts.data_block, chunk.read_to_end(&mut envelope.data)).await?;
+                if n < size {
+                    log::warn!("Connection closed in the middle of a BDAT chunk.");
                     break 'connection;
                 }
-            }
 
-            envelope.data = data;
+                if last {
+                    finish_transaction(
+                        handler,
+                        protocol,
+                        &mut envelope,
+                        writer,
+                        timeouts.data_done,
+                        transactions,
+                    )
+                    .await?;
+                    state = SessionState::Greeted;
+                } else {
+                    state = SessionState::Bdat;
+                    let reply = SmtpReply::new(250, (2, 0, 0), format!("{size} octets received"));
+                    write_reply(writer, &reply).await?;
+                }
+            }
+            "RSET" => {
+                if !args.is_empty() {
+                    write_reply(writer, &SmtpReply::new(501, (5, 5, 4), "Syntax: RSET")).await?;
+                    continue 'connection;
+                }
 
-            // Process the message
-            match handler.handle_data(&envelope).await {
-                Ok(response) => {
-                    log::debug!("Sent: {response}");
-                    writer
-                        .write_all(format!("{}\r\n", response).as_bytes())
-                        .await?;
-                    writer.flush().await?;
+                envelope = Envelope::default();
+                if state != SessionState::Connected {
+                    state = SessionState::Greeted;
+                }
+                write_reply(writer, &SmtpReply::ok()).await?;
+            }
+            // Like Postfix, only trust the attributes from authorized peers.
+            "XCLIENT" | "XFORWARD" if !xclient_allowed => {
+                log::warn!("Rejected {verb} from an unauthorized peer.");
+                write_reply(
+                    writer,
+                    &SmtpReply::new(550, (5, 7, 0), "Error: insufficient authorization"),
+                )
+                .await?;
+            }
+            "XCLIENT" | "XFORWARD"
+                if state != SessionState::Connected && state != SessionState::Greeted =>
+            {
+                write_reply(
+                    writer,
+                    &SmtpReply::new(503, (5, 5, 1), "Mail transaction in progress"),
+                )
+                .await?;
+            }
+            "XCLIENT" => match client.xclient(args) {
+                Ok(()) => {
+                    log::debug!("XCLIENT: {client}");
+                    // The session starts over as if the client had just connected.
+                    envelope = Envelope::default();
+                    state = SessionState::Connected;
+                    write_reply(writer, &protocol.greeting()).await?;
                 }
-                Err(e) => {
-                    log::debug!("Sent: {e}");
-                    writer.write_all(format!("{}\r\n", e).as_bytes()).await?;
-                    writer.flush().await?;
+                Err(e) => write_reply(writer, &e).await?,
+            },
+            "XFORWARD" => match client.xforward(args) {
+                Ok(()) => {
+                    log::debug!("XFORWARD: {client}");
+                    write_reply(writer, &SmtpReply::ok()).await?;
                 }
+                Err(e) => write_reply(writer, &e).await?,
+            },
+            "NOOP" => {
+                write_reply(writer, &SmtpReply::ok()).await?;
+            }
+            "QUIT" => {
+                write_reply(writer, &SmtpReply::new(221, (2, 0, 0), "Bye")).await?;
+                break 'connection;
+            }
+            _ => {
+                write_reply(
+                    writer,
+                    &SmtpReply::new(500, (5, 5, 1), "Command not recognized"),
+                )
+                .await?;
             }
-
-            envelope = Envelope {
-                mail_from: String::new(),
-                rcpt_to: Vec::new(),
-                data: Vec::new(),
-            };
-        } else if cmd.to_uppercase().starts_with("QUIT") {
-            writer.write_all(b"221 OK\r\n").await?;
-            writer.flush().await?;
-            break 'connection;
-        } else if cmd.to_uppercase().starts_with("RSET") {
-            envelope = Envelope {
-                mail_from: String::new(),
-                rcpt_to: Vec::new(),
-                data: Vec::new(),
-            };
-            writer.write_all(b"250 OK\r\n").await?;
-            writer.flush().await?;
-        } else if cmd.to_uppercase().starts_with("NOOP") {
-            writer.write_all(b"250 OK\r\n").await?;
-            writer.flush().await?;
-        } else {
-            writer.write_all(b"500 Command not recognized\r\n").await?;
-            writer.flush().await?;
         }
     }
 
+    writer.flush().await?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::esmtp::{Body, Notify, Ret};
+    use crate::outbound::OutgoingBeforeQueueHandler;
+    use crate::reinject::{MemoryReinjector, Reinjector};
+    use crate::utils::strip_final_crlf;
+    use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+    use rstest::*;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll};
+    use testresult::TestResult;
+    use tokio::io::{AsyncReadExt, ReadBuf};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Handler that accepts everything and captures envelopes instead of reinjecting them.
+    #[derive(Default)]
+    struct CaptureHandler {
+        reinjector: MemoryReinjector,
+    }
+
+    impl CaptureHandler {
+        fn envelopes(&self) -> Vec<Envelope> {
+            self.reinjector.envelopes()
+        }
+    }
+
+    #[async_trait]
+    impl SmtpHandler for CaptureHandler {
+        fn handle_mail(&self, _address: &str, _client: &ClientInfo) -> Result<(), SmtpReply> {
+            Ok(())
+        }
+
+        fn handle_rcpt(&self, address: &str, _envelope: &Envelope) -> Result<(), SmtpReply> {
+            match address.starts_with("rejected@") {
+                true => Err(SmtpReply::new(550, (5, 1, 1), "Recipient rejected")),
+                false => Ok(()),
+            }
+        }
+
+        fn check_data(&self, _envelope: &Envelope) -> Result<Option<Verdict>, SmtpReply> {
+            Ok(None)
+        }
+
+        fn check_recipients(
+            &self,
+            envelope: &Envelope,
+        ) -> (Option<Verdict>, Vec<Result<(), SmtpReply>>) {
+            let verdicts = envelope
+                .rcpt_to
+                .iter()
+                .map(|address| match address.starts_with("refused@") {
+                    true => Err(SmtpReply::new(523, (5, 7, 1), "Encryption Needed")),
+                    false => Ok(()),
+                })
+                .collect();
+            (None, verdicts)
+        }
+
+        async fn reinject_mail(
+            &self,
+            envelope: &Envelope,
+            delivery: Delivery,
+        ) -> Vec<Result<(), SmtpReply>> {
+            self.reinjector.reinject(envelope, delivery).await
+        }
+    }
+
+    /// Stream wrapper recording every write to check how replies are batched.
+    struct RecordingStream<S> {
+        inner: S,
+        writes: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for RecordingStream<S> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for RecordingStream<S> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if let Poll::Ready(Ok(n)) = poll {
+                let written = String::from_utf8_lossy(&buf[..n]).into_owned();
+                self.writes.lock().unwrap().push(written);
+            }
+            poll
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Reads the given number of complete, possibly multi-line, replies.
+    async fn read_replies<R>(reader: &mut R, count: usize) -> TestResult<Vec<String>>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let mut replies = Vec::new();
+        let mut reply = String::new();
+        while replies.len() < count {
+            let n = reader.read_line(&mut reply).await?;
+            assert!(n > 0, "connection closed after {replies:?}");
+            let last_line = reply.lines().last().unwrap_or_default();
+            if last_line.as_bytes().get(3) == Some(&b' ') {
+                replies.push(std::mem::take(&mut reply));
+            }
+        }
+        Ok(replies)
+    }
+
+    const EHLO_REPLY: &str = "250-filtermail\r\n\
+                              250-SIZE 1024\r\n\
+                              250-8BITMIME\r\n\
+                              250-PIPELINING\r\n\
+                              250-CHUNKING\r\n\
+                              250-ENHANCEDSTATUSCODES\r\n\
+                              250-DSN\r\n\
+                              250-XCLIENT NAME ADDR PORT PROTO HELO LOGIN\r\n\
+                              250-XFORWARD NAME ADDR PORT PROTO HELO IDENT SOURCE\r\n\
+                              250 SMTPUTF8\r\n";
+
+    const EHLO: &str = "EHLO localhost";
+    const MAIL: &str = "MAIL FROM:<alice@example.org>";
+    const RCPT: &str = "RCPT TO:<bob@example.org>";
+
+    /// Splits server output into complete, possibly multi-line, replies.
+    fn split_replies(output: &str) -> Vec<String> {
+        let mut replies = Vec::new();
+        let mut reply = String::new();
+        for line in output.split_inclusive("\r\n") {
+            reply.push_str(line);
+            if line.as_bytes().get(3) != Some(&b'-') {
+                replies.push(std::mem::take(&mut reply));
+            }
+        }
+        replies
+    }
+
+    /// Applies SMTP transparency the same way a client does.
+    fn dot_stuff(data: &[u8]) -> Vec<u8> {
+        let mut stuffed = Vec::new();
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if line.starts_with(b".") {
+                stuffed.push(b'.');
+            }
+            stuffed.extend_from_slice(line);
+        }
+        stuffed
+    }
+
+    /// Wraps raw DATA content into a complete SMTP session.
+    fn session_with_data(stuffed: &[u8]) -> Vec<u8> {
+        let mut input = b"HELO localhost\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<bob@example.org>\r\n\
+            DATA\r\n"
+            .to_vec();
+        input.extend_from_slice(stuffed);
+        input.extend_from_slice(b".\r\nQUIT\r\n");
+        input
+    }
+
+    /// Runs the server loop against the given client input and returns the server output.
+    async fn run_session(
+        handler: Arc<CaptureHandler>,
+        input: &[u8],
+        max_size: usize,
+    ) -> TestResult<String> {
+        run_protocol_session(handler, Protocol::Smtp, input, max_size).await
+    }
+
+    /// Runs the server loop speaking the protocol against the given client input.
+    async fn run_protocol_session(
+        handler: Arc<CaptureHandler>,
+        protocol: Protocol,
+        input: &[u8],
+        max_size: usize,
+    ) -> TestResult<String> {
+        run_handler_session(handler, protocol, true, input, max_size).await
+    }
+
+    /// Runs the server loop with any handler,
+    /// accepting `XCLIENT` and `XFORWARD` only if `xclient_allowed` is set.
+    async fn run_handler_session<H: SmtpHandler + 'static>(
+        handler: Arc<H>,
+        protocol: Protocol,
+        xclient_allowed: bool,
+        input: &[u8],
+        max_size: usize,
+    ) -> TestResult<String> {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let client = async move {
+            client.write_all(input).await?;
+            client.shutdown().await?;
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await?;
+            Ok::<_, std::io::Error>(output)
+        };
+        let (result, output) = tokio::join!(
+            handle_connection(
+                server,
+                xclient_allowed,
+                handler,
+                protocol,
+                max_size,
+                Timeouts::default(),
+                Arc::new(Semaphore::new(1)),
+                watch::channel(false).1,
+            ),
+            client
+        );
+        result?;
+        Ok(String::from_utf8(output?)?)
+    }
+
+    #[rstest]
+    #[case::empty(b".\r\n", DataRead::Message(b"".to_vec()))]
+    #[case::plain(b"a\r\nb\r\n.\r\n", DataRead::Message(b"a\r\nb\r\n".to_vec()))]
+    #[case::stuffed(b"..\r\n...\r\n..x\r\n.\r\n", DataRead::Message(b".\r\n..\r\n.x\r\n".to_vec()))]
+    #[case::non_utf8(b"\xfc\xdf\r\n.\r\n", DataRead::Message(b"\xfc\xdf\r\n".to_vec()))]
+    #[case::bare_lf(b"a\nb\r\n.\r\n", DataRead::Malformed)]
+    #[case::eof(b"a\r\n", DataRead::Malformed)]
+    #[case::too_large(b"0123456789abcdef\r\n.\r\n", DataRead::TooLarge)]
+    #[tokio::test]
+    async fn test_read_data(#[case] input: &[u8], #[case] expected: DataRead) -> TestResult {
+        let mut reader = input;
+        assert_eq!(
+            read_data(&mut reader, 16, &Timeouts::default()).await?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::complete(b"abc\r\nd", b"abc\r\n", LineRead::Complete, b"d")]
+    #[case::at_limit(b"abcdef\r\nd", b"abcdef\r\n", LineRead::Complete, b"d")]
+    #[case::too_long(b"abcdefg\r\nd", b"", LineRead::TooLong, b"d")]
+    #[case::eof(b"abc", b"abc", LineRead::Eof, b"")]
+    #[tokio::test]
+    async fn test_read_line(
+        #[case] input: &[u8],
+        #[case] expected_line: &[u8],
+        #[case] expected: LineRead,
+        #[case] rest: &[u8],
+    ) -> TestResult {
+        // Tiny buffer to check lines spanning several reads.
+        let mut reader = BufReader::with_capacity(2, input);
+        let mut line = Vec::new();
+        assert_eq!(read_line(&mut reader, &mut line, 8).await?, expected);
+        assert_eq!(line, expected_line);
+
+        let mut remaining = Vec::new();
+        reader.read_to_end(&mut remaining).await?;
+        assert_eq!(remaining, rest);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_data_line_too_long() -> TestResult {
+        let mut input = b"a\r\n".to_vec();
+        input.extend_from_slice(&[b'a'; MAX_TEXT_LINE]);
+        input.extend_from_slice(b"\r\nb\r\n.\r\nQUIT\r\n");
+
+        let mut reader = input.as_slice();
+        assert_eq!(
+            read_data(&mut reader, 4 * MAX_TEXT_LINE, &Timeouts::default()).await?,
+            DataRead::LineTooLong
+        );
+        assert_eq!(reader, b"QUIT\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rcpt_line_with_long_orcpt() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        // ORCPT may be up to 500 characters long (RFC 3461, section 4.2).
+        let orcpt = format!("rfc822;{}@example.org", "a".repeat(500 - 19));
+        let rcpt = format!("RCPT TO:<bob@example.org> NOTIFY=SUCCESS,FAILURE,DELAY ORCPT={orcpt}");
+        let input = format!(
+            "EHLO localhost\r\n\
+             NOOP {}\r\n\
+             MAIL FROM:<alice@example.org> SIZE=12345 RET=HDRS ENVID={}\r\n\
+             {rcpt}\r\n\
+             {rcpt}{}\r\n\
+             DATA\r\n\
+             body\r\n\
+             .\r\n\
+             QUIT\r\n",
+            "a".repeat(rcpt.len()),
+            "e".repeat(100),
+            " ".repeat(MAX_PARAMETERS_COMMAND_LINE - rcpt.len()),
+        );
+
+        let output = run_session(handler.clone(), input.as_bytes(), 1024 * 1024).await?;
+        let replies = split_replies(&output);
+        assert_eq!(
+            replies[2..6],
+            [
+                "500 5.5.2 Line too long\r\n",
+                "250 2.1.0 OK\r\n",
+                "250 2.1.5 OK\r\n",
+                "500 5.5.2 Line too long\r\n",
+            ]
+        );
+
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].rcpt_parameters[0].orcpt, Some(orcpt));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_line_too_long() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let mut input = b"EHLO localhost\r\nNOOP ".to_vec();
+        input.extend_from_slice(&[b'a'; MAX_COMMAND_LINE]);
+        input.extend_from_slice(b"\r\nMAIL FROM:<alice@example.org>\r\n");
+        input.extend_from_slice(b"RCPT TO:<bob@example.org>\r\nDATA\r\n");
+        input.extend_from_slice(&[b'a'; MAX_TEXT_LINE]);
+        input.extend_from_slice(b"\r\n.\r\nRCPT TO:<bob@example.org>\r\nQUIT\r\n");
+
+        let output = run_session(handler.clone(), &input, 1024).await?;
+
+        assert_eq!(
+            split_replies(&output),
+            [
+                "220 filtermail SMTP\r\n",
+                EHLO_REPLY,
+                "500 5.5.2 Line too long\r\n",
+                "250 2.1.0 OK\r\n",
+                "250 2.1.5 OK\r\n",
+                "354 End data with <CR><LF>.<CR><LF>\r\n",
+                "500 5.5.2 Line too long\r\n",
+                "503 5.5.1 Need MAIL before RCPT\r\n",
+                "221 2.0.0 Bye\r\n",
+            ]
+        );
+        assert!(handler.envelopes().is_empty());
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::dot_stuffed("test_data/dot-stuffed.eml")]
+    #[case::latin1("test_data/latin1.eml")]
+    #[tokio::test]
+    async fn test_data_transparency(#[case] file: &str) -> TestResult {
+        let message = std::fs::read(file)?;
+        let handler = Arc::new(CaptureHandler::default());
+
+        let output = run_session(
+            handler.clone(),
+            &session_with_data(&dot_stuff(&message)),
+            1024,
+        )
+        .await?;
+
+        assert!(
+            output.ends_with("250 2.0.0 OK\r\n221 2.0.0 Bye\r\n"),
+            "{output}"
+        );
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].data, message);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_command() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let output = run_session(handler, b"HELO \xff\r\nQUIT\r\n", 1024).await?;
+        assert_eq!(
+            output,
+            "220 filtermail SMTP\r\n500 5.5.2 Invalid command encoding\r\n221 2.0.0 Bye\r\n"
+        );
+        Ok(())
+    }
+
+    /// Sends the message through lettre like reinjection does
+    /// and checks that the server receives it unchanged.
+    #[rstest]
+    #[case::dot_stuffed("test_data/dot-stuffed.eml", "alice@example.org")]
+    #[case::latin1("test_data/latin1.eml", "alice@example.org")]
+    #[case::null_sender("test_data/mdn.eml", "")]
+    #[tokio::test]
+    async fn test_reinjection_unchanged(#[case] file: &str, #[case] mail_from: &str) -> TestResult {
+        let message = std::fs::read(file)?;
+        let handler = Arc::new(CaptureHandler::default());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let server_handler = handler.clone();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            handle_connection(
+                socket,
+                true,
+                server_handler,
+                Protocol::Smtp,
+                1024,
+                Timeouts::default(),
+                Arc::new(Semaphore::new(1)),
+                watch::channel(false).1,
+            )
+            .await
+            .unwrap();
+        });
+
+        let mailer = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1")
+            .port(port)
+            .build();
+        let reverse_path = match mail_from {
+            "" => None,
+            mail_from => Some(mail_from.parse()?),
+        };
+        let envelope =
+            lettre::address::Envelope::new(reverse_path, vec!["bob@example.org".parse()?])?;
+        mailer
+            .send_raw(&envelope, strip_final_crlf(&message))
+            .await?;
+
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].mail_from, mail_from);
+        assert_eq!(envelopes[0].data, message);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ehlo() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let output = run_session(handler, b"EHLO localhost\r\nQUIT\r\n", 1024).await?;
+        assert_eq!(
+            output,
+            format!("220 filtermail SMTP\r\n{EHLO_REPLY}221 2.0.0 Bye\r\n")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mail_from_size_rejected() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let output = run_session(
+            handler,
+            b"EHLO localhost\r\nMAIL FROM:<alice@example.org> SIZE=2048\r\nQUIT\r\n",
+            1024,
+        )
+        .await?;
+        assert!(
+            output.ends_with(
+                "552 5.3.4 Message size exceeds fixed maximum message size\r\n221 2.0.0 Bye\r\n"
+            ),
+            "{output}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pipelining() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = RecordingStream {
+            inner: server,
+            writes: writes.clone(),
+        };
+
+        let client = async move {
+            let (reader, mut writer) = tokio::io::split(client);
+            let mut reader = BufReader::new(reader);
+            let mut replies = read_replies(&mut reader, 1).await?;
+
+            writer.write_all(b"EHLO localhost\r\n").await?;
+            replies.extend(read_replies(&mut reader, 1).await?);
+
+            writer
+                .write_all(
+                    b"MAIL FROM:<alice@example.org>\r\n\
+                      RCPT TO:<bob@example.org>\r\n\
+                      RCPT TO:<carol@example.org>\r\n\
+                      DATA\r\n",
+                )
+                .await?;
+            replies.extend(read_replies(&mut reader, 4).await?);
+
+            writer
+                .write_all(b"Subject: a\r\n\r\nb\r\n.\r\nQUIT\r\n")
+                .await?;
+            replies.extend(read_replies(&mut reader, 2).await?);
+            TestResult::Ok(replies)
+        };
+        let (result, replies) = tokio::join!(
+            handle_connection(
+                server,
+                true,
+                handler.clone(),
+                Protocol::Smtp,
+                1024,
+                Timeouts::default(),
+                Arc::new(Semaphore::new(1)),
+                watch::channel(false).1,
+            ),
+            client
+        );
+        result?;
+
+        assert_eq!(
+            replies?,
+            [
+                "220 filtermail SMTP\r\n",
+                EHLO_REPLY,
+                "250 2.1.0 OK\r\n",
+                "250 2.1.5 OK\r\n",
+                "250 2.1.5 OK\r\n",
+                "354 End data with <CR><LF>.<CR><LF>\r\n",
+                "250 2.0.0 OK\r\n",
+                "221 2.0.0 Bye\r\n",
+            ]
+        );
+
+        // Replies to each command group are sent with a single write.
+        assert_eq!(
+            *writes.lock().unwrap(),
+            [
+                "220 filtermail SMTP\r\n",
+                EHLO_REPLY,
+                "250 2.1.0 OK\r\n250 2.1.5 OK\r\n250 2.1.5 OK\r\n\
+                 354 End data with <CR><LF>.<CR><LF>\r\n",
+                "250 2.0.0 OK\r\n221 2.0.0 Bye\r\n",
+            ]
+        );
+
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(
+            envelopes[0].rcpt_to,
+            ["bob@example.org", "carol@example.org"]
+        );
+        assert_eq!(envelopes[0].data, b"Subject: a\r\n\r\nb\r\n");
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("BDAT 0", Some((0, false)))]
+    #[case("BDAT 1024 LAST", Some((1024, true)))]
+    #[case("bdat 12 last", Some((12, true)))]
+    #[case("BDAT", None)]
+    #[case("BDAT -1", None)]
+    #[case("BDAT 12 FIRST", None)]
+    #[case("BDAT 12 LAST MORE", None)]
+    fn test_parse_bdat(#[case] cmd: &str, #[case] expected: Option<(usize, bool)>) {
+        assert_eq!(parse_bdat(cmd), expected);
+    }
+
+    #[rstest]
+    #[case::dot_stuffed("test_data/dot-stuffed.eml")]
+    #[case::latin1("test_data/latin1.eml")]
+    #[tokio::test]
+    async fn test_bdat(#[case] file: &str) -> TestResult {
+        let message = std::fs::read(file)?;
+        let (first, second) = message.split_at(message.len() / 2);
+        let handler = Arc::new(CaptureHandler::default());
+
+        let mut input = b"EHLO localhost\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<bob@example.org>\r\n"
+            .to_vec();
+        input.extend_from_slice(format!("BDAT {}\r\n", first.len()).as_bytes());
+        input.extend_from_slice(first);
+        input.extend_from_slice(format!("BDAT {} LAST\r\n", second.len()).as_bytes());
+        input.extend_from_slice(second);
+        input.extend_from_slice(b"QUIT\r\n");
+
+        let output = run_session(handler.clone(), &input, 1024).await?;
+
+        assert!(
+            output.ends_with(&format!(
+                "250 2.0.0 {} octets received\r\n250 2.0.0 OK\r\n221 2.0.0 Bye\r\n",
+                first.len()
+            )),
+            "{output}"
+        );
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].data, message);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bdat_too_large() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let mut input = b"EHLO localhost\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<bob@example.org>\r\n\
+            BDAT 1000\r\n"
+            .to_vec();
+        input.extend_from_slice(&[b'a'; 1000]);
+        input.extend_from_slice(b"BDAT 25 LAST\r\n");
+        input.extend_from_slice(&[b'a'; 25]);
+
+        let output = run_session(handler.clone(), &input, 1024).await?;
+
+        assert!(
+            output.ends_with(
+                "250 2.0.0 1000 octets received\r\n552 5.3.4 Message exceeds maximum size\r\n"
+            ),
+            "{output}"
+        );
+        assert!(handler.envelopes().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bdat_invalid_size() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let input = b"EHLO localhost\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<bob@example.org>\r\n\
+            BDAT x LAST\r\n\
+            NOOP\r\n";
+
+        let output = run_session(handler.clone(), input, 1024).await?;
+
+        // The chunk is not taken for a command.
+        assert!(
+            output.ends_with("250 2.1.5 OK\r\n501 5.5.4 Syntax: BDAT <size> [LAST]\r\n"),
+            "{output}"
+        );
+        assert!(handler.envelopes().is_empty());
+        Ok(())
+    }
+
+    /// Session state transitions: the commands leading to a state,
+    /// the command sent in that state and the expected reply to it.
+    #[rstest]
+    // Connected
+    #[case::connected_helo(&[], "HELO localhost", "250 filtermail")]
+    #[case::connected_ehlo_no_domain(&[], "EHLO", "501 5.5.4 Syntax: EHLO hostname")]
+    #[case::connected_mail(&[], MAIL, "503 5.5.1 Send HELO/EHLO first")]
+    #[case::connected_rcpt(&[], RCPT, "503 5.5.1 Need MAIL before RCPT")]
+    #[case::connected_data(&[], "DATA", "503 5.5.1 Need MAIL before DATA")]
+    #[case::connected_bdat(&[], "BDAT 0 LAST", "503 5.5.1 Need MAIL before BDAT")]
+    #[case::connected_rset(&[], "RSET", "250 2.0.0 OK")]
+    #[case::connected_rset_mail(&["RSET"], MAIL, "503 5.5.1 Send HELO/EHLO first")]
+    #[case::connected_noop(&[], "NOOP", "250 2.0.0 OK")]
+    #[case::connected_unknown(&[], "VRFY bob", "500 5.5.1 Command not recognized")]
+    #[case::connected_lhlo(&[], "LHLO localhost", "500 5.5.1 Command not recognized")]
+    // Greeted
+    #[case::greeted_mail(&[EHLO], MAIL, "250 2.1.0 OK")]
+    #[case::greeted_mail_syntax(&[EHLO], "MAIL <alice@example.org>", "501 5.5.4 Syntax: MAIL FROM:<address>")]
+    #[case::greeted_mail_no_address(&[EHLO], "MAIL FROM:", "501 5.1.7 Invalid address in MAIL FROM")]
+    #[case::greeted_mail_null(&[EHLO], "MAIL FROM:<>", "250 2.1.0 OK")]
+    #[case::greeted_mail_parameter(&[EHLO], "MAIL FROM:<alice@example.org> OTHER=1", "555 5.5.4 Unsupported MAIL FROM parameter")]
+    #[case::greeted_mail_utf8(&[EHLO], "MAIL FROM:<jörg@example.org>", "553 5.6.7 Non-ASCII address requires SMTPUTF8")]
+    #[case::greeted_rcpt(&[EHLO], RCPT, "503 5.5.1 Need MAIL before RCPT")]
+    #[case::greeted_data(&[EHLO], "DATA", "503 5.5.1 Need MAIL before DATA")]
+    #[case::greeted_bdat(&[EHLO], "BDAT 0 LAST", "503 5.5.1 Need MAIL before BDAT")]
+    #[case::greeted_bdat_chunk_skipped(&[EHLO, "BDAT 6\r\nQUIT"], "NOOP", "250 2.0.0 OK")]
+    #[case::greeted_rset_args(&[EHLO], "RSET now", "501 5.5.4 Syntax: RSET")]
+    #[case::greeted_xforward(&[EHLO], "XFORWARD ADDR=192.0.2.1", "250 2.0.0 OK")]
+    #[case::greeted_xforward_invalid(&[EHLO], "XFORWARD COLOR=red", "501 5.5.4 Bad XFORWARD attribute")]
+    #[case::greeted_xclient(&[EHLO], "XCLIENT ADDR=192.0.2.1", "220 filtermail SMTP")]
+    #[case::xclient_mail(&[EHLO, "XCLIENT ADDR=192.0.2.1"], MAIL, "503 5.5.1 Send HELO/EHLO first")]
+    // Mail
+    #[case::mail_mail(&[EHLO, MAIL], MAIL, "503 5.5.1 Nested MAIL command")]
+    #[case::mail_rcpt(&[EHLO, MAIL], RCPT, "250 2.1.5 OK")]
+    #[case::mail_rcpt_syntax(&[EHLO, MAIL], "RCPT <bob@example.org>", "501 5.5.4 Syntax: RCPT TO:<address>")]
+    #[case::mail_rcpt_no_address(&[EHLO, MAIL], "RCPT TO:<>", "501 5.1.3 Invalid address in RCPT TO")]
+    #[case::mail_rcpt_parameter(&[EHLO, MAIL], "RCPT TO:<bob@example.org> SIZE=1", "555 5.5.4 Unsupported RCPT TO parameter")]
+    #[case::mail_data(&[EHLO, MAIL], "DATA", "503 5.5.1 Need RCPT before DATA")]
+    #[case::mail_bdat(&[EHLO, MAIL], "BDAT 0 LAST", "503 5.5.1 Need RCPT before BDAT")]
+    #[case::mail_rset_rcpt(&[EHLO, MAIL, "RSET"], RCPT, "503 5.5.1 Need MAIL before RCPT")]
+    #[case::mail_ehlo_rcpt(&[EHLO, MAIL, EHLO], RCPT, "503 5.5.1 Need MAIL before RCPT")]
+    #[case::mail_xforward(&[EHLO, MAIL], "XFORWARD ADDR=192.0.2.1", "503 5.5.1 Mail transaction in progress")]
+    // Rcpt
+    #[case::rcpt_rcpt(&[EHLO, MAIL, RCPT], "RCPT TO:<carol@example.org>", "250 2.1.5 OK")]
+    #[case::rcpt_rcpt_rejected(&[EHLO, MAIL, RCPT], "RCPT TO:<rejected@example.org>", "550 5.1.1 Recipient rejected")]
+    #[case::mail_rejected_data(&[EHLO, MAIL, "RCPT TO:<rejected@example.org>"], "DATA", "503 5.5.1 Need RCPT before DATA")]
+    #[case::rcpt_mail(&[EHLO, MAIL, RCPT], MAIL, "503 5.5.1 Nested MAIL command")]
+    #[case::rcpt_data(&[EHLO, MAIL, RCPT], "DATA", "354 End data with <CR><LF>.<CR><LF>")]
+    #[case::rcpt_data_args(&[EHLO, MAIL, RCPT], "DATA now", "501 5.5.4 Syntax: DATA")]
+    #[case::rcpt_bdat(&[EHLO, MAIL, RCPT], "BDAT 0", "250 2.0.0 0 octets received")]
+    #[case::rcpt_bdat_syntax(&[EHLO, MAIL, RCPT], "BDAT", "501 5.5.4 Syntax: BDAT <size> [LAST]")]
+    #[case::rcpt_rset_data(&[EHLO, MAIL, RCPT, "RSET"], "DATA", "503 5.5.1 Need MAIL before DATA")]
+    // Bdat
+    #[case::bdat_bdat(&[EHLO, MAIL, RCPT, "BDAT 0"], "BDAT 0 LAST", "250 2.0.0 OK")]
+    #[case::bdat_rcpt(&[EHLO, MAIL, RCPT, "BDAT 0"], RCPT, "503 5.5.1 RCPT not allowed after BDAT")]
+    #[case::bdat_mail(&[EHLO, MAIL, RCPT, "BDAT 0"], MAIL, "503 5.5.1 Nested MAIL command")]
+    #[case::bdat_data(&[EHLO, MAIL, RCPT, "BDAT 0"], "DATA", "503 5.5.1 DATA not allowed after BDAT")]
+    #[case::bdat_rset_bdat(&[EHLO, MAIL, RCPT, "BDAT 0", "RSET"], "BDAT 0 LAST", "503 5.5.1 Need MAIL before BDAT")]
+    // Back to greeted after a transaction
+    #[case::data_done_rcpt(&[EHLO, MAIL, RCPT, "DATA", "."], RCPT, "503 5.5.1 Need MAIL before RCPT")]
+    #[case::data_done_mail(&[EHLO, MAIL, RCPT, "DATA", "."], MAIL, "250 2.1.0 OK")]
+    #[case::bdat_done_rcpt(&[EHLO, MAIL, RCPT, "BDAT 0 LAST"], RCPT, "503 5.5.1 Need MAIL before RCPT")]
+    #[case::bdat_done_mail(&[EHLO, MAIL, RCPT, "BDAT 0 LAST"], MAIL, "250 2.1.0 OK")]
+    #[tokio::test]
+    async fn test_session_state(
+        #[case] commands: &[&str],
+        #[case] command: &str,
+        #[case] expected: &str,
+    ) -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let mut input = String::new();
+        for line in commands.iter().chain([&command]) {
+            input.push_str(line);
+            input.push_str("\r\n");
+        }
+        input.push_str("QUIT\r\n");
+
+        let output = run_session(handler, input.as_bytes(), 1024).await?;
+
+        // Skip the greeting and replies to the commands leading to the state.
+        let replies = split_replies(&output);
+        assert_eq!(
+            replies
+                .get(commands.len() + 1)
+                .map(|reply| reply.trim_end()),
+            Some(expected),
+            "{output}"
+        );
+        Ok(())
+    }
+
+    /// The client sends the input and then stalls without closing the connection.
+    #[rstest]
+    #[case::greeting(b"")]
+    #[case::mail(b"EHLO localhost\r\n")]
+    #[case::rcpt(b"EHLO localhost\r\nMAIL FROM:<alice@example.org>\r\n")]
+    #[case::data_init(
+        b"EHLO localhost\r\nMAIL FROM:<alice@example.org>\r\nRCPT TO:<bob@example.org>\r\nDATA\r\n"
+    )]
+    #[case::data_block(b"EHLO localhost\r\nMAIL FROM:<alice@example.org>\r\nRCPT TO:<bob@example.org>\r\nDATA\r\na\r\n")]
+    #[case::bdat_chunk(b"EHLO localhost\r\nMAIL FROM:<alice@example.org>\r\nRCPT TO:<bob@example.org>\r\nBDAT 10\r\nabc")]
+    #[tokio::test]
+    async fn test_timeout(#[case] input: &[u8]) -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let timeouts = Timeouts {
+            greeting: Duration::from_millis(50),
+            mail: Duration::from_millis(50),
+            rcpt: Duration::from_millis(50),
+            data_init: Duration::from_millis(50),
+            data_block: Duration::from_millis(50),
+            data_done: Duration::from_millis(50),
+            ..Timeouts::default()
+        };
+
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let client = async move {
+            client.write_all(input).await?;
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await?;
+            Ok::<_, std::io::Error>(output)
+        };
+        let (result, output) = tokio::join!(
+            handle_connection(
+                server,
+                true,
+                handler.clone(),
+                Protocol::Smtp,
+                1024,
+                timeouts,
+                Arc::new(Semaphore::new(1)),
+                watch::channel(false).1,
+            ),
+            client
+        );
+        result?;
+
+        let output = String::from_utf8(output?)?;
+        assert!(
+            output.ends_with("421 4.4.2 filtermail Error: timeout exceeded\r\n"),
+            "{output}"
+        );
+        assert!(handler.envelopes().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_session_limit() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let limits = Limits {
+            max_sessions: 1,
+            max_transactions: 1,
+        };
+        tokio::spawn(async move {
+            run_smtp_server(
+                Listener::Tcp(listener),
+                handler,
+                Protocol::Smtp,
+                1024,
+                Timeouts::default(),
+                limits,
+                AuthorizedHosts::default(),
+                watch::channel(false).1,
+            )
+            .await
+            .unwrap();
+        });
+
+        let mut first = BufReader::new(TcpStream::connect(addr).await?);
+        assert_eq!(
+            read_replies(&mut first, 1).await?,
+            ["220 filtermail SMTP\r\n"]
+        );
+
+        let mut second = TcpStream::connect(addr).await?;
+        let mut output = String::new();
+        second.read_to_string(&mut output).await?;
+        assert_eq!(
+            output,
+            "421 4.3.2 Too many connections, try again later\r\n"
+        );
+
+        // The slot is free again once the first session ends.
+        first.write_all(b"QUIT\r\n").await?;
+        assert_eq!(read_replies(&mut first, 1).await?, ["221 2.0.0 Bye\r\n"]);
+        let mut rest = Vec::new();
+        first.read_to_end(&mut rest).await?;
+
+        let mut third = BufReader::new(TcpStream::connect(addr).await?);
+        assert_eq!(
+            read_replies(&mut third, 1).await?,
+            ["220 filtermail SMTP\r\n"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_idle() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let (client, server) = tokio::io::duplex(64 * 1024);
+
+        let client = async move {
+            let (reader, mut writer) = tokio::io::split(client);
+            let mut reader = BufReader::new(reader);
+            let mut replies = read_replies(&mut reader, 1).await?;
+            writer.write_all(b"EHLO localhost\r\n").await?;
+            replies.extend(read_replies(&mut reader, 1).await?);
+
+            shutdown_tx.send_replace(true);
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).await?;
+            replies.push(rest);
+            TestResult::Ok(replies)
+        };
+        let connection = handle_connection(
+            server,
+            true,
+            handler,
+            Protocol::Smtp,
+            1024,
+            Timeouts::default(),
+            Arc::new(Semaphore::new(1)),
+            shutdown,
+        );
+        let (result, replies) = tokio::join!(connection, client);
+        result?;
+
+        assert_eq!(
+            replies?,
+            [
+                "220 filtermail SMTP\r\n",
+                EHLO_REPLY,
+                "421 4.3.2 filtermail shutting down, try again later\r\n",
+            ]
+        );
+        Ok(())
+    }
+
+    /// A transaction started before the shutdown is completed.
+    #[tokio::test]
+    async fn test_shutdown_in_transaction() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let (client, server) = tokio::io::duplex(64 * 1024);
+
+        let client = async move {
+            let (reader, mut writer) = tokio::io::split(client);
+            let mut reader = BufReader::new(reader);
+            writer
+                .write_all(b"EHLO localhost\r\nMAIL FROM:<alice@example.org>\r\n")
+                .await?;
+            let mut replies = read_replies(&mut reader, 3).await?;
+
+            shutdown_tx.send_replace(true);
+            writer
+                .write_all(b"RCPT TO:<bob@example.org>\r\nDATA\r\n")
+                .await?;
+            replies.extend(read_replies(&mut reader, 2).await?);
+            writer.write_all(b"a\r\n.\r\n").await?;
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).await?;
+            replies.push(rest);
+            TestResult::Ok(replies)
+        };
+        let connection = handle_connection(
+            server,
+            true,
+            handler.clone(),
+            Protocol::Smtp,
+            1024,
+            Timeouts::default(),
+            Arc::new(Semaphore::new(1)),
+            shutdown,
+        );
+        let (result, replies) = tokio::join!(connection, client);
+        result?;
+
+        assert_eq!(
+            replies?,
+            [
+                "220 filtermail SMTP\r\n",
+                EHLO_REPLY,
+                "250 2.1.0 OK\r\n",
+                "250 2.1.5 OK\r\n",
+                "354 End data with <CR><LF>.<CR><LF>\r\n",
+                "250 2.0.0 OK\r\n\
+                 421 4.3.2 filtermail shutting down, try again later\r\n",
+            ]
+        );
+        assert_eq!(handler.envelopes().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_deadline() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let timeouts = Timeouts {
+            shutdown: Duration::from_millis(50),
+            ..Timeouts::default()
+        };
+        let limits = Limits {
+            max_sessions: 10,
+            max_transactions: 10,
+        };
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let server = tokio::spawn(async move {
+            run_smtp_server(
+                Listener::Tcp(listener),
+                handler,
+                Protocol::Smtp,
+                1024,
+                timeouts,
+                limits,
+                AuthorizedHosts::default(),
+                shutdown,
+            )
+            .await
+            .unwrap();
+        });
+
+        // This session stays in a transaction until it is aborted.
+        let mut client = BufReader::new(TcpStream::connect(addr).await?);
+        client
+            .write_all(b"EHLO localhost\r\nMAIL FROM:<alice@example.org>\r\n")
+            .await?;
+        read_replies(&mut client, 3).await?;
+
+        shutdown_tx.send_replace(true);
+        server.await?;
+
+        assert!(TcpStream::connect(addr).await.is_err());
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await?;
+        assert!(rest.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multiple_transactions() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let input = b"EHLO localhost\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<bob@example.org>\r\n\
+            DATA\r\n\
+            first\r\n\
+            .\r\n\
+            MAIL FROM:<carol@example.org>\r\n\
+            RCPT TO:<dave@example.org>\r\n\
+            RCPT TO:<erin@example.org>\r\n\
+            BDAT 8 LAST\r\n\
+            second\r\n\
+            QUIT\r\n";
+
+        run_session(handler.clone(), input, 1024).await?;
+
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), 2);
+        assert_eq!(envelopes[0].mail_from, "alice@example.org");
+        assert_eq!(envelopes[0].rcpt_to, ["bob@example.org"]);
+        assert_eq!(envelopes[0].data, b"first\r\n");
+        assert_eq!(envelopes[1].mail_from, "carol@example.org");
+        assert_eq!(
+            envelopes[1].rcpt_to,
+            ["dave@example.org", "erin@example.org"]
+        );
+        assert_eq!(envelopes[1].data, b"second\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_envelope_parameters() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let input = "EHLO localhost\r\n\
+            MAIL FROM:<Alice@Example.org> BODY=8BITMIME SMTPUTF8 RET=HDRS ENVID=id1\r\n\
+            RCPT TO:<\"Bob Smith\"@example.org> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;bob@example.org\r\n\
+            RCPT TO:<@relay.example.net:jörg@bücher.example>\r\n\
+            DATA\r\n\
+            body\r\n\
+            .\r\n\
+            QUIT\r\n";
+
+        run_session(handler.clone(), input.as_bytes(), 1024).await?;
+
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), 1);
+        let envelope = &envelopes[0];
+        assert_eq!(envelope.mail_from, "Alice@example.org");
+        assert_eq!(
+            envelope.mail_parameters,
+            MailParameters {
+                body: Some(Body::EightBitMime),
+                smtputf8: true,
+                ret: Some(Ret::Headers),
+                envid: Some("id1".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            envelope.rcpt_to,
+            ["\"Bob Smith\"@example.org", "jörg@bücher.example"]
+        );
+        assert_eq!(
+            envelope.rcpt_parameters,
+            [
+                RcptParameters {
+                    notify: Some(Notify {
+                        success: true,
+                        failure: true,
+                        delay: false
+                    }),
+                    orcpt: Some("rfc822;bob@example.org".to_string()),
+                },
+                RcptParameters::default(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_envelope_client() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let input = b"XCLIENT LOGIN=alice+40example.org\r\n\
+            EHLO localhost\r\n\
+            XFORWARD NAME=mail.example.net ADDR=192.0.2.1 HELO=mx.example.net\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<bob@example.org>\r\n\
+            DATA\r\n\
+            body\r\n\
+            .\r\n\
+            QUIT\r\n";
+
+        run_session(handler.clone(), input, 1024).await?;
+
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(
+            envelopes[0].client,
+            ClientInfo {
+                name: Some("mail.example.net".to_string()),
+                addr: Some("192.0.2.1".to_string()),
+                helo: Some("mx.example.net".to_string()),
+                login: Some("alice@example.org".to_string()),
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_xclient_unauthorized() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let input = b"EHLO localhost\r\n\
+            XCLIENT LOGIN=mallory+40example.org\r\n\
+            XFORWARD NAME=mail.example.net ADDR=192.0.2.1 HELO=mx.example.net\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<bob@example.org>\r\n\
+            DATA\r\n\
+            body\r\n\
+            .\r\n\
+            QUIT\r\n";
+
+        let output =
+            run_handler_session(handler.clone(), Protocol::Smtp, false, input, 1024).await?;
+        let replies = split_replies(&output);
+        assert!(!replies[1].contains("XCLIENT") && !replies[1].contains("XFORWARD"));
+        assert_eq!(
+            replies[2..4],
+            [
+                "550 5.7.0 Error: insufficient authorization\r\n",
+                "550 5.7.0 Error: insufficient authorization\r\n",
+            ]
+        );
+
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].client, ClientInfo::default());
+        Ok(())
+    }
+
+    /// A client can't get a fresh rate limit quota by claiming another login.
+    #[tokio::test]
+    async fn test_xclient_rate_limit_key() -> TestResult {
+        let config = Config::parse(
+            "[params]\n\
+             mail_domain = example.org\n\
+             max_user_send_per_minute = 1\n\
+             max_user_send_burst_size = 1\n",
+        )?;
+        let handler = Arc::new(OutgoingBeforeQueueHandler::new(Arc::new(config)));
+        let input = b"EHLO localhost\r\n\
+            XCLIENT LOGIN=first\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RSET\r\n\
+            XCLIENT LOGIN=second\r\n\
+            MAIL FROM:<alice@example.org>\r\n";
+
+        let output = run_handler_session(handler, Protocol::Smtp, false, input, 1024).await?;
+        let replies = split_replies(&output);
+        assert_eq!(replies[3], "250 2.1.0 OK\r\n");
+        assert!(replies[6].starts_with("450 4.7.1 Too much mail from <alice@example.org>"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rcpt_rejected() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let input = b"EHLO localhost\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<bob@example.org>\r\n\
+            RCPT TO:<rejected@example.org>\r\n\
+            RCPT TO:<carol@example.org> NOTIFY=NEVER\r\n\
+            DATA\r\n\
+            body\r\n\
+            .\r\n\
+            QUIT\r\n";
+
+        run_session(handler.clone(), input, 1024).await?;
+
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(
+            envelopes[0].rcpt_to,
+            ["bob@example.org", "carol@example.org"]
+        );
+        assert_eq!(
+            envelopes[0].rcpt_parameters[1].notify,
+            Some(Notify::default())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lmtp() -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let input = b"EHLO localhost\r\n\
+            LHLO localhost\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<bob@example.org>\r\n\
+            RCPT TO:<refused@example.org>\r\n\
+            RCPT TO:<carol@example.org> NOTIFY=NEVER\r\n\
+            DATA\r\n\
+            body\r\n\
+            .\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<refused@example.org>\r\n\
+            BDAT 6 LAST\r\n\
+            body\r\n\
+            QUIT\r\n";
+
+        let output = run_protocol_session(handler.clone(), Protocol::Lmtp, input, 1024).await?;
+        let replies = split_replies(&output);
+        assert_eq!(
+            replies,
+            [
+                "220 filtermail LMTP\r\n",
+                "500 5.5.1 Command not recognized\r\n",
+                EHLO_REPLY,
+                "250 2.1.0 OK\r\n",
+                "250 2.1.5 OK\r\n",
+                "250 2.1.5 OK\r\n",
+                "250 2.1.5 OK\r\n",
+                "354 End data with <CR><LF>.<CR><LF>\r\n",
+                "250 2.0.0 OK\r\n",
+                "523 5.7.1 Encryption Needed\r\n",
+                "250 2.0.0 OK\r\n",
+                "250 2.1.0 OK\r\n",
+                "250 2.1.5 OK\r\n",
+                "523 5.7.1 Encryption Needed\r\n",
+                "221 2.0.0 Bye\r\n",
+            ]
+        );
+
+        // Only the accepted recipients are reinjected, the second message not at all.
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(
+            envelopes[0].rcpt_to,
+            ["bob@example.org", "carol@example.org"]
+        );
+        assert_eq!(
+            envelopes[0].rcpt_parameters[1].notify,
+            Some(Notify::default())
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::smtp(Protocol::Smtp, &["452 4.2.2 Mailbox full\r\n"], 0)]
+    #[case::lmtp(
+        Protocol::Lmtp,
+        &["250 2.0.0 OK\r\n", "452 4.2.2 Mailbox full\r\n"],
+        1
+    )]
+    #[tokio::test]
+    async fn test_reinject_partially_failed(
+        #[case] protocol: Protocol,
+        #[case] data_replies: &[&str],
+        #[case] delivered: usize,
+    ) -> TestResult {
+        let handler = Arc::new(CaptureHandler::default());
+        let hello = match protocol {
+            Protocol::Smtp => "EHLO",
+            Protocol::Lmtp => "LHLO",
+        };
+        let input = format!(
+            "{hello} localhost\r\n\
+            MAIL FROM:<alice@example.org>\r\n\
+            RCPT TO:<bob@example.org>\r\n\
+            RCPT TO:<full@example.org>\r\n\
+            DATA\r\n\
+            body\r\n\
+            .\r\n\
+            QUIT\r\n"
+        );
+
+        let output =
+            run_protocol_session(handler.clone(), protocol, input.as_bytes(), 1024).await?;
+        let replies = split_replies(&output);
+        assert_eq!(replies[6..replies.len() - 1], *data_replies);
+
+        // Over SMTP, the message is not delivered to anybody as there is one reply for all.
+        let envelopes = handler.envelopes();
+        assert_eq!(envelopes.len(), delivered);
+        if let Some(envelope) = envelopes.first() {
+            assert_eq!(envelope.rcpt_to, ["bob@example.org"]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,768 @@
+//! On-disk spool for messages the reinjection target could not take right away.
+//!
+//! Every message is a file with the `MAIL FROM`, `RCPT TO` and `XFORWARD` commands
+//! of its transaction, followed by `DATA` and the message content.
+//! The modification time of a queued file is the time of its next delivery attempt.
+
+use crate::bounce::bounce;
+use crate::esmtp::{parse_mail_from, parse_rcpt_to};
+use crate::reinject::{Delivery, Reinjector};
+use crate::reply::SmtpReply;
+use crate::smtp_server::{Envelope, shutdown_requested};
+use async_trait::async_trait;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
+
+/// Delay before the first retry and shortest delay between retries.
+const MIN_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Longest delay between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Time after which messages still failing temporarily are given up on.
+const MAX_LIFETIME: Duration = Duration::from_secs(5 * 24 * 3600);
+
+/// Interval at which the queue is checked for messages due for delivery.
+const SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Makes the IDs of messages spooled by this process unique.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Directory holding the spooled messages of a role.
+///
+/// Messages are written to `tmp`, moved to `queue` once they are on disk
+/// and to `failed` if the reinjection target rejects them permanently
+/// or they could not be delivered within [`MAX_LIFETIME`].
+#[derive(Debug, Clone)]
+pub struct Spool {
+    dir: PathBuf,
+    /// Name of this host in the bounces sent to the senders of failed messages.
+    hostname: String,
+}
+
+/// A message in the spool.
+#[derive(Debug)]
+pub struct SpooledMessage {
+    /// Name of the file, starting with the Unix time the message was spooled at.
+    pub id: String,
+    /// Envelope and content of the message.
+    pub envelope: Envelope,
+    /// Time of the next delivery attempt, `None` if delivery failed permanently.
+    pub next_attempt: Option<SystemTime>,
+}
+
+impl fmt::Display for SpooledMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} bytes ", self.id, self.envelope.data.len())?;
+        match self.next_attempt {
+            Some(next_attempt) => match next_attempt.duration_since(SystemTime::now()) {
+                Ok(delay) => write!(f, "retry in {}s", delay.as_secs())?,
+                Err(_) => write!(f, "retry now")?,
+            },
+            None => write!(f, "failed")?,
+        }
+        write!(f, "\n  from <{}>", self.envelope.mail_from)?;
+        for rcpt_to in &self.envelope.rcpt_to {
+            write!(f, "\n  to <{rcpt_to}>")?;
+        }
+        Ok(())
+    }
+}
+
+impl Spool {
+    /// Uses the spool in `dir`, which is created when the first message is stored.
+    pub fn new(dir: PathBuf, hostname: &str) -> Self {
+        Self {
+            dir,
+            hostname: hostname.to_string(),
+        }
+    }
+
+    fn tmp(&self) -> PathBuf {
+        self.dir.join("tmp")
+    }
+
+    fn queue(&self) -> PathBuf {
+        self.dir.join("queue")
+    }
+
+    fn failed(&self) -> PathBuf {
+        self.dir.join("failed")
+    }
+
+    /// Stores a message for delivery after [`MIN_BACKOFF`], returning its ID.
+    pub async fn store(&self, envelope: &Envelope) -> io::Result<String> {
+        let id = new_id();
+        let next_attempt = SystemTime::now() + MIN_BACKOFF;
+        self.write(&self.queue(), &id, envelope, next_attempt)
+            .await?;
+        Ok(id)
+    }
+
+    /// Writes a message into `dir`, replacing the one with the same ID.
+    ///
+    /// The file only appears in `dir` once it is completely on disk.
+    async fn write(
+        &self,
+        dir: &Path,
+        id: &str,
+        envelope: &Envelope,
+        modified: SystemTime,
+    ) -> io::Result<()> {
+        create_dir(&self.tmp()).await?;
+        create_dir(dir).await?;
+
+        let tmp = self.tmp().join(id);
+        let mut file = fs::File::create(&tmp).await?;
+        file.write_all(&serialize(envelope)).await?;
+        let file = file.into_std().await;
+        file.set_modified(modified)?;
+        fs::File::from_std(file).sync_all().await?;
+
+        fs::rename(&tmp, dir.join(id)).await?;
+        fs::File::open(dir).await?.sync_all().await
+    }
+
+    /// Lists the queued messages, followed by those that failed permanently.
+    pub async fn list(&self) -> io::Result<Vec<SpooledMessage>> {
+        let mut messages = Vec::new();
+        for (dir, queued) in [(self.queue(), true), (self.failed(), false)] {
+            for (id, path) in entries(&dir).await? {
+                let message = async {
+                    let modified = fs::metadata(&path).await?.modified()?;
+                    Ok::<_, io::Error>((read(&path).await?, modified))
+                };
+                // The message may have been delivered in the meantime.
+                let (envelope, modified) = match message.await {
+                    Ok(message) => message,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                };
+                messages.push(SpooledMessage {
+                    id,
+                    envelope,
+                    next_attempt: queued.then_some(modified),
+                });
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Makes all queued messages due for delivery, returning how many there are.
+    pub async fn flush(&self) -> io::Result<usize> {
+        let entries = entries(&self.queue()).await?;
+        for (_, path) in &entries {
+            match set_next_attempt(path, SystemTime::now()).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(entries.len())
+    }
+
+    /// Tries to deliver the queued messages that are due.
+    ///
+    /// Messages are removed once delivered to all recipients.
+    /// A copy for the recipients rejected permanently, or still failing after
+    /// [`MAX_LIFETIME`], is kept in `failed` and the sender gets a bounce about them.
+    /// Errors with a single message are logged and the others are still tried.
+    pub async fn retry(&self, reinjector: &dyn Reinjector) -> io::Result<()> {
+        let now = SystemTime::now();
+        for (id, path) in entries(&self.queue()).await? {
+            match self.retry_message(&id, &path, reinjector, now).await {
+                Ok(()) => {}
+                // The message may have been delivered by another process in the meantime.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => log::error!("Failed to process spooled message {id}: {e}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Tries to deliver a queued message if it is due.
+    async fn retry_message(
+        &self,
+        id: &str,
+        path: &Path,
+        reinjector: &dyn Reinjector,
+        now: SystemTime,
+    ) -> io::Result<()> {
+        if fs::metadata(path).await?.modified()? > now {
+            return Ok(());
+        }
+
+        let envelope = match read(path).await {
+            Ok(envelope) => envelope,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(e),
+            Err(e) => {
+                log::error!("Failed to read spooled message {id}: {e}");
+                return self.fail(id).await;
+            }
+        };
+        let mut outcomes = reinjector.reinject(&envelope, Delivery::PerRecipient).await;
+        for (address, outcome) in envelope.rcpt_to.iter().zip(&outcomes) {
+            match outcome {
+                Ok(()) => log::info!("Delivered spooled message {id} to <{address}>"),
+                Err(reply) => {
+                    log::warn!("Failed to deliver spooled message {id} to <{address}>: {reply}")
+                }
+            }
+        }
+
+        let expired = age(id, now) >= MAX_LIFETIME;
+        if expired && outcomes.iter().any(is_transient) {
+            log::error!(
+                "Spooled message {id} not delivered within {}s, giving up",
+                MAX_LIFETIME.as_secs()
+            );
+        }
+        let failed: Vec<bool> = outcomes
+            .iter()
+            .map(|outcome| is_permanent(outcome) || expired && is_transient(outcome))
+            .collect();
+        if failed.contains(&true) {
+            let rejected = envelope.with_recipients(failed.iter().copied());
+            let failed_id = new_id();
+            self.write(&self.failed(), &failed_id, &rejected, now)
+                .await?;
+            log::error!("Spooled message {id} rejected, kept as {failed_id}");
+
+            let undeliverable: Vec<_> = outcomes
+                .iter()
+                .zip(&failed)
+                .map(|(outcome, &failed)| if failed { outcome.clone() } else { Ok(()) })
+                .collect();
+            if let Some(bounce) = bounce(&envelope, &undeliverable, &self.hostname, id, now) {
+                let bounce_id = new_id();
+                self.write(&self.queue(), &bounce_id, &bounce, now).await?;
+                log::info!(
+                    "Bouncing spooled message {id} to <{}> as {bounce_id}",
+                    envelope.mail_from
+                );
+            }
+            for (outcome, failed) in outcomes.iter_mut().zip(failed) {
+                if failed {
+                    *outcome = Ok(());
+                }
+            }
+        }
+
+        if outcomes.iter().any(is_transient) {
+            let deferred = envelope.with_recipients(outcomes.iter().map(is_transient));
+            let delay = backoff(id, now);
+            self.write(&self.queue(), id, &deferred, now + delay)
+                .await?;
+            log::info!("Retrying spooled message {id} in {}s", delay.as_secs());
+            Ok(())
+        } else {
+            fs::remove_file(path).await
+        }
+    }
+
+    /// Retries delivery of the queued messages until shutdown is requested.
+    pub async fn run(self, reinjector: Box<dyn Reinjector>, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            if let Err(e) = self.retry(reinjector.as_ref()).await {
+                log::error!("Failed to process spool {}: {e}", self.dir.display());
+            }
+            tokio::select! {
+                () = tokio::time::sleep(SCAN_INTERVAL) => {}
+                () = shutdown_requested(&mut shutdown) => return,
+            }
+        }
+    }
+
+    /// Moves a queued message to `failed`.
+    async fn fail(&self, id: &str) -> io::Result<()> {
+        create_dir(&self.failed()).await?;
+        fs::rename(self.queue().join(id), self.failed().join(id)).await
+    }
+}
+
+/// Builds a new message ID from the current time, the process ID and a sequence number.
+fn new_id() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    format!(
+        "{}.{}.{}",
+        now.unwrap_or_default().as_secs(),
+        std::process::id(),
+        SEQUENCE.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Whether delivery to a recipient failed temporarily.
+fn is_transient(outcome: &Result<(), SmtpReply>) -> bool {
+    outcome.as_ref().is_err_and(SmtpReply::is_transient)
+}
+
+/// Whether delivery to a recipient failed permanently.
+fn is_permanent(outcome: &Result<(), SmtpReply>) -> bool {
+    outcome.as_ref().is_err_and(|reply| !reply.is_transient())
+}
+
+/// Creates a spool directory only accessible to filtermail.
+async fn create_dir(dir: &Path) -> io::Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .await
+}
+
+/// Lists the IDs and paths of the files in a spool directory, oldest first.
+async fn entries(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut read_dir = match fs::read_dir(dir).await {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    while let Some(entry) = read_dir.next_entry().await? {
+        if let Ok(id) = entry.file_name().into_string() {
+            entries.push((id, entry.path()));
+        }
+    }
+    entries.sort_by_cached_key(|(id, _)| spooled_at(id));
+    Ok(entries)
+}
+
+/// Time a message was spooled at, from the start of its ID.
+fn spooled_at(id: &str) -> SystemTime {
+    let secs = id
+        .split('.')
+        .next()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or_default();
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// How long a message has been in the spool.
+fn age(id: &str, now: SystemTime) -> Duration {
+    now.duration_since(spooled_at(id)).unwrap_or_default()
+}
+
+/// Delay before the next attempt, as long as the message has been in the spool.
+///
+/// This doubles the delay with every attempt, from [`MIN_BACKOFF`] up to [`MAX_BACKOFF`].
+fn backoff(id: &str, now: SystemTime) -> Duration {
+    age(id, now).clamp(MIN_BACKOFF, MAX_BACKOFF)
+}
+
+/// Sets the time of the next delivery attempt of a queued message.
+async fn set_next_attempt(path: &Path, time: SystemTime) -> io::Result<()> {
+    let file = fs::File::options().write(true).open(path).await?;
+    file.into_std().await.set_modified(time)
+}
+
+/// Reads a spooled message.
+async fn read(path: &Path) -> io::Result<Envelope> {
+    parse(&fs::read(path).await?)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid spool file"))
+}
+
+/// Writes the transaction in SMTP syntax.
+fn serialize(envelope: &Envelope) -> Vec<u8> {
+    let mut header = format!(
+        "MAIL FROM:<{}>{}\r\n",
+        envelope.mail_from, envelope.mail_parameters
+    );
+    for (index, rcpt_to) in envelope.rcpt_to.iter().enumerate() {
+        let parameters = envelope
+            .rcpt_parameters
+            .get(index)
+            .cloned()
+            .unwrap_or_default();
+        header += &format!("RCPT TO:<{rcpt_to}>{parameters}\r\n");
+    }
+    for command in envelope.client.xforward_commands() {
+        header += &command;
+    }
+    header += "DATA\r\n";
+
+    let mut content = header.into_bytes();
+    content.extend_from_slice(&envelope.data);
+    content
+}
+
+/// Parses a transaction written by [`serialize`].
+fn parse(content: &[u8]) -> Option<Envelope> {
+    let mut envelope = Envelope::default();
+    let mut offset = 0;
+    for line in content.split_inclusive(|&b| b == b'\n') {
+        offset += line.len();
+        let line = std::str::from_utf8(line).ok()?.strip_suffix("\r\n")?;
+        if let Some(args) = line.strip_prefix("MAIL FROM:") {
+            let mail_from = parse_mail_from(args).ok()?;
+            envelope.mail_from = mail_from.address;
+            envelope.mail_parameters = mail_from.parameters;
+        } else if let Some(args) = line.strip_prefix("RCPT TO:") {
+            let rcpt_to = parse_rcpt_to(args, envelope.mail_parameters.smtputf8).ok()?;
+            envelope.rcpt_to.push(rcpt_to.address);
+            envelope.rcpt_parameters.push(rcpt_to.parameters);
+        } else if let Some(args) = line.strip_prefix("XFORWARD ") {
+            envelope.client.xforward(args).ok()?;
+        } else if line == "DATA" {
+            envelope.data = content.get(offset..)?.to_vec();
+            return Some(envelope);
+        } else {
+            return None;
+        }
+    }
+    None
+}
+
+/// Reinjects messages, spooling them for the recipients the target can't take right now.
+///
+/// Spooled messages are acknowledged to the client and retried by [`Spool::run`].
+/// With [`Delivery::AllOrNothing`], messages are only spooled if no recipient
+/// was rejected permanently, as the client would not learn about the others.
+pub struct SpoolingReinjector {
+    reinjector: Box<dyn Reinjector>,
+    spool: Spool,
+}
+
+impl SpoolingReinjector {
+    /// Wraps `reinjector`, storing messages in `spool` on transient failures.
+    pub fn new(reinjector: Box<dyn Reinjector>, spool: Spool) -> Self {
+        Self { reinjector, spool }
+    }
+}
+
+#[async_trait]
+impl Reinjector for SpoolingReinjector {
+    async fn reinject(
+        &self,
+        envelope: &Envelope,
+        delivery: Delivery,
+    ) -> Vec<Result<(), SmtpReply>> {
+        let mut outcomes = self.reinjector.reinject(envelope, delivery).await;
+        if !outcomes.iter().any(is_transient)
+            || delivery == Delivery::AllOrNothing && outcomes.iter().any(is_permanent)
+        {
+            return outcomes;
+        }
+
+        let deferred = envelope.with_recipients(outcomes.iter().map(is_transient));
+        match self.spool.store(&deferred).await {
+            Ok(id) => {
+                log::warn!(
+                    "Reinjection deferred for {} recipients, spooled message as {id}",
+                    deferred.rcpt_to.len()
+                );
+                for outcome in outcomes.iter_mut().filter(|outcome| is_transient(outcome)) {
+                    *outcome = Ok(());
+                }
+            }
+            Err(e) => log::error!("Failed to spool message: {e}"),
+        }
+        outcomes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::esmtp::{MailParameters, Notify, RcptParameters, Ret};
+    use crate::reinject::MemoryReinjector;
+    use crate::xclient::ClientInfo;
+    use rstest::*;
+    use testresult::TestResult;
+
+    /// Fails every delivery with the same reply.
+    struct FailingReinjector(SmtpReply);
+
+    #[async_trait]
+    impl Reinjector for FailingReinjector {
+        async fn reinject(
+            &self,
+            envelope: &Envelope,
+            _delivery: Delivery,
+        ) -> Vec<Result<(), SmtpReply>> {
+            vec![Err(self.0.clone()); envelope.rcpt_to.len()]
+        }
+    }
+
+    /// Creates a spool in an empty directory.
+    fn test_spool(name: &str) -> std::io::Result<Spool> {
+        let dir =
+            std::env::temp_dir().join(format!("filtermail-spool-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        Ok(Spool::new(dir, "example.org"))
+    }
+
+    fn envelope() -> Envelope {
+        Envelope {
+            mail_from: "alice@example.org".to_string(),
+            rcpt_to: vec![
+                "bob@example.org".to_string(),
+                "carol@example.org".to_string(),
+            ],
+            mail_parameters: MailParameters {
+                ret: Some(Ret::Headers),
+                envid: Some("QQ314159".to_string()),
+                ..Default::default()
+            },
+            rcpt_parameters: vec![
+                RcptParameters {
+                    notify: Some(Notify {
+                        success: true,
+                        failure: true,
+                        delay: false,
+                    }),
+                    orcpt: Some("rfc822;bob@example.org".to_string()),
+                },
+                RcptParameters::default(),
+            ],
+            client: ClientInfo {
+                addr: Some("192.0.2.1".to_string()),
+                helo: Some("mail.example.net".to_string()),
+                ..Default::default()
+            },
+            data: b"Subject: test\r\n\r\nDATA\r\nbody\r\n".to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_serialize() {
+        let envelope = envelope();
+        let content = serialize(&envelope);
+        assert!(content.starts_with(
+            b"MAIL FROM:<alice@example.org> RET=HDRS ENVID=QQ314159\r\n\
+              RCPT TO:<bob@example.org> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;bob@example.org\r\n\
+              RCPT TO:<carol@example.org>\r\n\
+              XFORWARD ADDR=192.0.2.1 HELO=mail.example.net\r\n\
+              DATA\r\n"
+        ));
+
+        let parsed = parse(&content).unwrap();
+        assert_eq!(parsed.mail_from, envelope.mail_from);
+        assert_eq!(parsed.rcpt_to, envelope.rcpt_to);
+        assert_eq!(parsed.mail_parameters, envelope.mail_parameters);
+        assert_eq!(parsed.rcpt_parameters, envelope.rcpt_parameters);
+        assert_eq!(parsed.client, envelope.client);
+        assert_eq!(parsed.data, envelope.data);
+
+        assert!(parse(b"MAIL FROM:<alice@example.org>\r\nRCPT TO:<bob@example.org>\r\n").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_spool_flush() -> TestResult {
+        let spool = test_spool("flush")?;
+        let id = spool.store(&envelope()).await?;
+
+        let messages = spool.list().await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, id);
+        assert!(messages[0].next_attempt > Some(SystemTime::now()));
+
+        // Nothing is due until the spool is flushed.
+        let reinjector = MemoryReinjector::default();
+        spool.retry(&reinjector).await?;
+        assert!(reinjector.envelopes().is_empty());
+
+        assert_eq!(spool.flush().await?, 1);
+        spool.retry(&reinjector).await?;
+        let envelopes = reinjector.envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].rcpt_to, envelope().rcpt_to);
+        assert!(spool.list().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spool_retry_partial() -> TestResult {
+        let spool = test_spool("partial")?;
+        let mut envelope = envelope();
+        envelope.rcpt_to[1] = "full@example.org".to_string();
+        let id = spool.store(&envelope).await?;
+        spool.flush().await?;
+
+        let reinjector = MemoryReinjector::default();
+        spool.retry(&reinjector).await?;
+        assert_eq!(reinjector.envelopes()[0].rcpt_to, ["bob@example.org"]);
+
+        let messages = spool.list().await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, id);
+        assert_eq!(messages[0].envelope.rcpt_to, ["full@example.org"]);
+        assert_eq!(
+            messages[0].envelope.rcpt_parameters,
+            [RcptParameters::default()]
+        );
+        assert!(messages[0].next_attempt > Some(SystemTime::now()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spool_retry_deferred() -> TestResult {
+        let spool = test_spool("deferred")?;
+        spool.store(&envelope()).await?;
+        spool.flush().await?;
+
+        let reply = SmtpReply::new(451, (4, 4, 1), "Connection refused");
+        spool.retry(&FailingReinjector(reply)).await?;
+        let messages = spool.list().await?;
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].next_attempt > Some(SystemTime::now() + MIN_BACKOFF / 2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spool_retry_rejected() -> TestResult {
+        let spool = test_spool("rejected")?;
+        spool.store(&envelope()).await?;
+        spool.flush().await?;
+
+        let reply = SmtpReply::new(550, (5, 1, 1), "User unknown");
+        spool.retry(&FailingReinjector(reply)).await?;
+        let messages = spool.list().await?;
+        assert_eq!(messages.len(), 2);
+
+        // The bounce is due right away and can't bounce itself.
+        let bounce = &messages[0];
+        assert!(bounce.next_attempt <= Some(SystemTime::now()));
+        assert_eq!(bounce.envelope.mail_from, "");
+        assert_eq!(bounce.envelope.rcpt_to, ["alice@example.org"]);
+        let data = String::from_utf8(bounce.envelope.data.clone())?;
+        assert!(data.contains("Final-Recipient: rfc822; bob@example.org\r\n"));
+        assert!(data.contains("Final-Recipient: rfc822; carol@example.org\r\n"));
+
+        let failed = &messages[1];
+        assert_eq!(failed.next_attempt, None);
+        assert_eq!(failed.envelope.rcpt_to, envelope().rcpt_to);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spool_retry_null_sender() -> TestResult {
+        let spool = test_spool("null-sender")?;
+        let mut envelope = envelope();
+        envelope.mail_from = String::new();
+        spool.store(&envelope).await?;
+        spool.flush().await?;
+
+        let reply = SmtpReply::new(550, (5, 1, 1), "User unknown");
+        spool.retry(&FailingReinjector(reply)).await?;
+        let messages = spool.list().await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].next_attempt, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spool_retry_expired() -> TestResult {
+        let spool = test_spool("expired")?;
+        let mut envelope = envelope();
+        envelope.rcpt_to[1] = "full@example.org".to_string();
+        let now = SystemTime::now();
+        let spooled = now.duration_since(UNIX_EPOCH)? - MAX_LIFETIME;
+        let id = format!("{}.1.0", spooled.as_secs());
+        spool.write(&spool.queue(), &id, &envelope, now).await?;
+
+        let reinjector = MemoryReinjector::default();
+        spool.retry(&reinjector).await?;
+        let messages = spool.list().await?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].next_attempt, None);
+        assert_eq!(messages[1].envelope.rcpt_to, ["full@example.org"]);
+
+        // The bounce is delivered with the next pass.
+        spool.retry(&reinjector).await?;
+        let envelopes = reinjector.envelopes();
+        assert_eq!(envelopes.len(), 2);
+        assert_eq!(envelopes[1].mail_from, "");
+        assert_eq!(envelopes[1].rcpt_to, ["alice@example.org"]);
+        let data = String::from_utf8(envelopes[1].data.clone())?;
+        assert!(data.contains("Final-Recipient: rfc822; full@example.org\r\n"));
+        assert!(!data.contains("Final-Recipient: rfc822; bob@example.org\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spool_retry_error() -> TestResult {
+        let spool = test_spool("error")?;
+        let mut expired = envelope();
+        expired.rcpt_to[1] = "full@example.org".to_string();
+        let now = SystemTime::now();
+        let spooled = now.duration_since(UNIX_EPOCH)? - MAX_LIFETIME;
+        let id = format!("{}.1.0", spooled.as_secs());
+        spool.write(&spool.queue(), &id, &expired, now).await?;
+        spool.store(&envelope()).await?;
+        spool.flush().await?;
+
+        // Keeping the expired message in `failed` fails, the other one is still delivered.
+        std::fs::write(spool.failed(), b"")?;
+        let reinjector = MemoryReinjector::default();
+        spool.retry(&reinjector).await?;
+        assert_eq!(reinjector.envelopes().len(), 2);
+        let queued = entries(&spool.queue()).await?;
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].0, id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() {
+        let spooled = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let id = "1700000000.4711.0";
+        assert_eq!(backoff(id, spooled), MIN_BACKOFF);
+        assert_eq!(
+            backoff(id, spooled + Duration::from_secs(300)),
+            Duration::from_secs(300)
+        );
+        assert_eq!(backoff(id, spooled + MAX_BACKOFF * 5), MAX_BACKOFF);
+    }
+
+    #[rstest]
+    #[case::transient(SmtpReply::new(451, (4, 4, 1), "Connection refused"), Ok(()), 1)]
+    #[case::permanent(
+        SmtpReply::new(554, (5, 7, 1), "Rejected"),
+        Err(SmtpReply::new(554, (5, 7, 1), "Rejected")),
+        0
+    )]
+    #[tokio::test]
+    async fn test_spooling_reinjector(
+        #[case] reply: SmtpReply,
+        #[case] expected: Result<(), SmtpReply>,
+        #[case] spooled: usize,
+    ) -> TestResult {
+        let spool = test_spool(&format!("reinjector-{}", reply.code))?;
+        let reinjector = SpoolingReinjector::new(Box::new(FailingReinjector(reply)), spool.clone());
+        let outcomes = reinjector
+            .reinject(&envelope(), Delivery::AllOrNothing)
+            .await;
+        assert_eq!(outcomes, [expected.clone(), expected]);
+        assert_eq!(spool.list().await?.len(), spooled);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::all_or_nothing(Delivery::AllOrNothing, &["bob@example.org", "full@example.org"])]
+    #[case::per_recipient(Delivery::PerRecipient, &["full@example.org"])]
+    #[tokio::test]
+    async fn test_spooling_reinjector_partial(
+        #[case] delivery: Delivery,
+        #[case] spooled: &[&str],
+    ) -> TestResult {
+        let spool = test_spool(&format!("reinjector-{delivery:?}"))?;
+        let reinjector =
+            SpoolingReinjector::new(Box::new(MemoryReinjector::default()), spool.clone());
+        let mut envelope = envelope();
+        envelope.rcpt_to[1] = "full@example.org".to_string();
+
+        let outcomes = reinjector.reinject(&envelope, delivery).await;
+        assert_eq!(outcomes, [Ok(()), Ok(())]);
+        let messages = spool.list().await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].envelope.rcpt_to, spooled);
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! Integration with systemd socket activation and service notifications.
+
+use listenfd::ListenFd;
+use std::env;
+use std::ffi::OsStr;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// Listening sockets passed by systemd, see `sd_listen_fds_with_names(3)`.
+pub struct ActivatedListeners {
+    fds: ListenFd,
+    names: Vec<String>,
+    /// Roles served by this process, which the sockets are taken for.
+    roles: Vec<&'static str>,
+}
+
+impl std::fmt::Debug for ActivatedListeners {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ActivatedListeners")
+            .field("count", &self.fds.len())
+            .field("names", &self.names)
+            .field("roles", &self.roles)
+            .finish()
+    }
+}
+
+impl ActivatedListeners {
+    /// Reads `LISTEN_FDS` and `LISTEN_FDNAMES` from the environment.
+    ///
+    /// `LISTEN_FDS` and `LISTEN_PID` are removed so child processes don't take the sockets.
+    /// This changes the environment, so it must be called before any other thread is started.
+    /// `LISTEN_FDNAMES` is left in place, it is ignored without the others.
+    pub fn from_env() -> Self {
+        let names = env::var("LISTEN_FDNAMES")
+            .map(|names| names.split(':').map(ToString::to_string).collect())
+            .unwrap_or_default();
+        Self {
+            fds: ListenFd::from_env(),
+            names,
+            roles: Vec::new(),
+        }
+    }
+
+    /// Sets the roles served by this process, which the sockets are taken for.
+    pub fn for_roles(mut self, roles: &[&'static str]) -> Self {
+        self.roles = roles.to_vec();
+        self
+    }
+
+    /// Takes the TCP listener for the given role.
+    ///
+    /// Returns `None` if systemd did not pass a socket for the role.
+    pub fn take_tcp(&mut self, role: &str) -> std::io::Result<Option<std::net::TcpListener>> {
+        match socket_index(&self.names, self.fds.len(), role, &self.roles)? {
+            Some(index) => self.fds.take_tcp_listener(index),
+            None => Ok(None),
+        }
+    }
+
+    /// Takes the Unix domain socket listener for the given role.
+    ///
+    /// Returns `None` if systemd did not pass a socket for the role.
+    pub fn take_unix(
+        &mut self,
+        role: &str,
+    ) -> std::io::Result<Option<std::os::unix::net::UnixListener>> {
+        match socket_index(&self.names, self.fds.len(), role, &self.roles)? {
+            Some(index) => self.fds.take_unix_listener(index),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Finds the index of the socket named after the role with `FileDescriptorName=`.
+///
+/// If a single role is served, a single socket is used for it whatever its name,
+/// so a socket unit without `FileDescriptorName=` works as well.
+/// With several roles, a socket not named after one of them is an error,
+/// as it is not clear which role it is meant for.
+fn socket_index(
+    names: &[String],
+    count: usize,
+    role: &str,
+    roles: &[&str],
+) -> std::io::Result<Option<usize>> {
+    if let Some(index) = names.iter().position(|name| name == role) {
+        return Ok((index < count).then_some(index));
+    }
+    if let [_] = roles {
+        return Ok((count == 1).then_some(0));
+    }
+
+    let unnamed = (0..count).find(|&index| {
+        names
+            .get(index)
+            .is_none_or(|name| !roles.contains(&name.as_str()))
+    });
+    match unnamed {
+        Some(index) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "socket {} passed by systemd needs FileDescriptorName= set to one of: {}",
+                names.get(index).map_or("without a name", String::as_str),
+                roles.join(", ")
+            ),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Sends service state changes to systemd, see `sd_notify(3)`.
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+impl Notifier {
+    /// Connects to the socket from `NOTIFY_SOCKET`.
+    ///
+    /// Returns `None` if the service is not run with `Type=notify`.
+    pub fn from_env() -> std::io::Result<Option<Self>> {
+        env::var_os("NOTIFY_SOCKET")
+            .map(|path| Self::new(&path))
+            .transpose()
+    }
+
+    /// Creates a notifier for the socket at the given path.
+    ///
+    /// A leading `@` refers to a socket in the abstract namespace.
+    pub fn new(path: &OsStr) -> std::io::Result<Self> {
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+        })
+    }
+
+    /// Sends a state such as `READY=1`.
+    pub fn notify(&self, state: &str) -> std::io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+}
+
+/// Returns how often `WATCHDOG=1` has to be sent, if the watchdog is enabled for this process.
+///
+/// Notifications are sent at half the interval configured with `WatchdogSec=`.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = env::var("WATCHDOG_PID")
+        && pid.parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    Some(Duration::from_micros(usec) / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+    use testresult::TestResult;
+
+    #[rstest]
+    #[case::named(&["outgoing", "incoming"], 2, "incoming", Some(1))]
+    #[case::missing(&["outgoing", "incoming"], 2, "other", None)]
+    #[case::single_unnamed(&[], 1, "incoming", Some(0))]
+    #[case::single_other_name(&["filtermail.socket"], 1, "outgoing", Some(0))]
+    #[case::several_unnamed(&[], 2, "incoming", None)]
+    #[case::name_without_fd(&["outgoing", "incoming"], 1, "incoming", None)]
+    fn test_socket_index(
+        #[case] names: &[&str],
+        #[case] count: usize,
+        #[case] role: &str,
+        #[case] expected: Option<usize>,
+    ) -> TestResult {
+        let names: Vec<String> = names.iter().map(ToString::to_string).collect();
+        assert_eq!(socket_index(&names, count, role, &[role])?, expected);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::named(&["outgoing", "incoming"], 2, "incoming", Some(1))]
+    #[case::other_role_only(&["incoming"], 1, "outgoing", None)]
+    fn test_socket_index_two_roles(
+        #[case] names: &[&str],
+        #[case] count: usize,
+        #[case] role: &str,
+        #[case] expected: Option<usize>,
+    ) -> TestResult {
+        let names: Vec<String> = names.iter().map(ToString::to_string).collect();
+        let roles = ["incoming", "outgoing"];
+        assert_eq!(socket_index(&names, count, role, &roles)?, expected);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::single_unnamed(&[], 1, "incoming")]
+    #[case::single_other_name(&["filtermail.socket"], 1, "outgoing")]
+    #[case::second_unnamed(&["incoming"], 2, "outgoing")]
+    fn test_socket_index_two_roles_unnamed(
+        #[case] names: &[&str],
+        #[case] count: usize,
+        #[case] role: &str,
+    ) {
+        let names: Vec<String> = names.iter().map(ToString::to_string).collect();
+        let result = socket_index(&names, count, role, &["incoming", "outgoing"]);
+        assert!(result.is_err_and(|e| e.kind() == std::io::ErrorKind::InvalidInput));
+    }
+
+    #[rstest]
+    #[case::path(false)]
+    #[case::abstract_namespace(true)]
+    fn test_notify(#[case] abstract_namespace: bool) -> TestResult {
+        let dir = std::env::temp_dir().join(format!("filtermail-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("notify-{abstract_namespace}"));
+        let _ = std::fs::remove_file(&path);
+
+        let (systemd, notify_socket) = if abstract_namespace {
+            let name = path.to_string_lossy().into_owned();
+            let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+            (UnixDatagram::bind_addr(&addr)?, format!("@{name}"))
+        } else {
+            let notify_socket = path.to_string_lossy().into_owned();
+            (UnixDatagram::bind(&path)?, notify_socket)
+        };
+
+        let notifier = Notifier::new(OsStr::new(&notify_socket))?;
+        notifier.notify("READY=1")?;
+        notifier.notify("STOPPING=1")?;
+
+        let mut buf = [0; 64];
+        let n = systemd.recv(&mut buf)?;
+        assert_eq!(&buf[..n], b"READY=1");
+        let n = systemd.recv(&mut buf)?;
+        assert_eq!(&buf[..n], b"STOPPING=1");
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+//! Trace headers recording that a message passed filtermail.
+//!
+//! A `Received` header (RFC 5321, section 4.4) shows the hop and the role that handled it
+//! and an `X-Filtermail-Verdict` header tells why the message was accepted.
+
+use crate::config::Role;
+use crate::smtp_server::{Envelope, Protocol};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the header carrying the [`Verdict`].
+pub const VERDICT_HEADER: &str = "X-Filtermail-Verdict";
+
+/// Reason for accepting a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The message is end-to-end encrypted.
+    Encrypted,
+    /// The message is a Secure-Join request.
+    Securejoin,
+    /// The message is a delivery status notification from a mailer daemon.
+    MailerDaemon,
+    /// The message is unencrypted and delivered to recipients accepting cleartext.
+    Cleartext,
+    /// The message is unencrypted and sent by or to a passthrough address.
+    Passthrough,
+    /// The message is an Autocrypt Setup Message sent to oneself.
+    AutocryptSetup,
+}
+
+impl Verdict {
+    /// Whether the message is encrypted, as opposed to allowed unencrypted.
+    pub fn is_encrypted(self) -> bool {
+        matches!(self, Verdict::Encrypted | Verdict::Securejoin)
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Verdict::Encrypted => "encrypted",
+            Verdict::Securejoin => "securejoin",
+            Verdict::MailerDaemon => "mailer-daemon",
+            Verdict::Cleartext => "cleartext",
+            Verdict::Passthrough => "passthrough",
+            Verdict::AutocryptSetup => "autocrypt-setup",
+        })
+    }
+}
+
+/// Adds the trace headers to the messages reinjected for a role.
+#[derive(Debug, Clone)]
+pub struct Trace {
+    hostname: String,
+    role: Role,
+    protocol: Protocol,
+}
+
+impl Trace {
+    /// Creates the trace for messages received by `hostname` for `role` over `protocol`.
+    pub fn new(hostname: impl Into<String>, role: Role, protocol: Protocol) -> Self {
+        Self {
+            hostname: hostname.into(),
+            role,
+            protocol,
+        }
+    }
+
+    /// Copy of the envelope with the trace headers prepended to the message.
+    ///
+    /// For incoming messages, spoofed headers are removed first:
+    /// any `X-Filtermail-Verdict` header and `Received` headers claiming to be from this filter.
+    pub fn stamp(&self, envelope: &Envelope) -> Envelope {
+        let data = match self.role {
+            Role::Incoming => strip(&envelope.data, |field| self.is_spoofed(field)),
+            Role::Outgoing => envelope.data.clone(),
+        };
+
+        let mut headers = self.received(envelope, SystemTime::now());
+        if let Some(verdict) = envelope.verdict {
+            headers.push_str(&format!("{VERDICT_HEADER}: {verdict}\r\n"));
+        }
+        let mut stamped = headers.into_bytes();
+        stamped.extend_from_slice(&data);
+
+        let mut mail_parameters = envelope.mail_parameters.clone();
+        if let Some(size) = &mut mail_parameters.size {
+            *size += stamped.len().saturating_sub(envelope.data.len());
+        }
+        Envelope {
+            mail_parameters,
+            data: stamped,
+            ..envelope.clone()
+        }
+    }
+
+    /// Builds the `Received` header for a message, folded before each clause after the first.
+    fn received(&self, envelope: &Envelope, time: SystemTime) -> String {
+        let client = &envelope.client;
+        let mut clauses = Vec::new();
+        if client.helo.is_some() || client.addr.is_some() {
+            let mut from = format!("from {}", client.helo.as_deref().unwrap_or("unknown"));
+            if let Some(addr) = &client.addr {
+                let name = client.name.as_deref().unwrap_or("unknown");
+                from.push_str(&format!(" ({name} [{addr}])"));
+            }
+            clauses.push(from);
+        }
+
+        let with = match self.protocol {
+            Protocol::Smtp => "ESMTP",
+            Protocol::Lmtp => "LMTP",
+        };
+        clauses.push(format!(
+            "by {} (filtermail {}) with {with}",
+            self.hostname, self.role
+        ));
+        // Like Postfix, only reveal the recipient if there is a single one.
+        if let [recipient] = envelope.rcpt_to.as_slice() {
+            clauses.push(format!("for <{recipient}>"));
+        }
+        format!(
+            "Received: {}; {}\r\n",
+            clauses.join("\r\n\t"),
+            format_date(time)
+        )
+    }
+
+    /// Whether a header field looks like one added by this filter.
+    fn is_spoofed(&self, field: &[u8]) -> bool {
+        if is_verdict(field) {
+            return true;
+        }
+        let field = String::from_utf8_lossy(field);
+        let Some((name, value)) = field.split_once(':') else {
+            return false;
+        };
+        let name = name.trim_end();
+
+        let value = value
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+        let by = format!("by {} (filtermail", self.hostname.to_lowercase());
+        name.eq_ignore_ascii_case("Received") && value.contains(&by)
+    }
+}
+
+/// Copy of an incoming envelope without `X-Filtermail-Verdict` headers, `None` if it has none.
+///
+/// Used when the trace headers are not added,
+/// so a verdict forged by the sender never reaches the mailbox.
+pub fn strip_verdict(envelope: &Envelope) -> Option<Envelope> {
+    let (fields, _) = header_fields(&envelope.data);
+    if !fields.into_iter().any(is_verdict) {
+        return None;
+    }
+    Some(Envelope {
+        data: strip(&envelope.data, is_verdict),
+        ..envelope.clone()
+    })
+}
+
+/// Whether a header field is an `X-Filtermail-Verdict` header.
+fn is_verdict(field: &[u8]) -> bool {
+    field
+        .split(|&byte| byte == b':')
+        .next()
+        .is_some_and(|name| {
+            name.trim_ascii_end()
+                .eq_ignore_ascii_case(VERDICT_HEADER.as_bytes())
+        })
+}
+
+/// Removes the spoofed header fields from the header section of a message.
+fn strip(data: &[u8], is_spoofed: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+    let (fields, body) = header_fields(data);
+    let mut result = Vec::with_capacity(data.len());
+    for field in fields {
+        if is_spoofed(field) {
+            log::warn!(
+                "Removed spoofed header: {}",
+                String::from_utf8_lossy(field).trim_end()
+            );
+        } else {
+            result.extend_from_slice(field);
+        }
+    }
+    result.extend_from_slice(body);
+    result
+}
+
+/// Splits a message into its header fields, including folded lines, and the rest.
+///
+/// The rest starts with the empty line separating the header section from the body.
+pub(crate) fn header_fields(data: &[u8]) -> (Vec<&[u8]>, &[u8]) {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+    for line in data.split_inclusive(|&byte| byte == b'\n') {
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        let folded = line.starts_with(b" ") || line.starts_with(b"\t");
+        if !folded && end > start {
+            fields.push(data.get(start..end).unwrap_or_default());
+            start = end;
+        }
+        end += line.len();
+    }
+    if end > start {
+        fields.push(data.get(start..end).unwrap_or_default());
+    }
+    (fields, data.get(end..).unwrap_or_default())
+}
+
+/// Formats a time as an RFC 5322 date in UTC, such as `Fri, 16 Oct 2026 12:00:00 +0000`.
+pub(crate) fn format_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec", "Jan", "Feb",
+    ];
+
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = seconds / 86400;
+
+    // Civil date from the days since 1970-01-01,
+    // see <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+    // Years start in March, so the leap day is the last day of the year.
+    let shifted = days + 719_468;
+    let era = shifted / 146_097;
+    let day_of_era = shifted % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let year = era * 400 + year_of_era + u64::from(month >= 10);
+
+    let weekday = usize::try_from(days % 7).unwrap_or_default();
+    let month = usize::try_from(month).unwrap_or_default();
+    format!(
+        "{}, {day} {} {year} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS.get(weekday).copied().unwrap_or_default(),
+        MONTHS.get(month).copied().unwrap_or_default(),
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::esmtp::MailParameters;
+    use crate::xclient::ClientInfo;
+    use rstest::*;
+    use std::time::Duration;
+
+    #[rstest]
+    #[case::epoch(0, "Thu, 1 Jan 1970 00:00:00 +0000")]
+    #[case::leap_day(951_782_400, "Tue, 29 Feb 2000 00:00:00 +0000")]
+    #[case::end_of_year(1_704_067_199, "Sun, 31 Dec 2023 23:59:59 +0000")]
+    #[case::recent(1_792_152_000, "Fri, 16 Oct 2026 12:00:00 +0000")]
+    fn test_format_date(#[case] seconds: u64, #[case] expected: &str) {
+        assert_eq!(
+            format_date(UNIX_EPOCH + Duration::from_secs(seconds)),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_received() {
+        let trace = Trace::new("chat.example.org", Role::Incoming, Protocol::Smtp);
+        let mut envelope = Envelope {
+            rcpt_to: vec!["bob@chat.example.org".to_string()],
+            client: ClientInfo {
+                name: Some("mail.example.net".to_string()),
+                addr: Some("192.0.2.1".to_string()),
+                helo: Some("mx.example.net".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            trace.received(&envelope, UNIX_EPOCH),
+            "Received: from mx.example.net (mail.example.net [192.0.2.1])\r\n\
+             \tby chat.example.org (filtermail incoming) with ESMTP\r\n\
+             \tfor <bob@chat.example.org>; Thu, 1 Jan 1970 00:00:00 +0000\r\n"
+        );
+
+        envelope.rcpt_to.push("carol@chat.example.org".to_string());
+        envelope.client = ClientInfo::default();
+        let trace = Trace::new("chat.example.org", Role::Outgoing, Protocol::Lmtp);
+        assert_eq!(
+            trace.received(&envelope, UNIX_EPOCH),
+            "Received: by chat.example.org (filtermail outgoing) with LMTP; \
+             Thu, 1 Jan 1970 00:00:00 +0000\r\n"
+        );
+    }
+
+    #[rstest]
+    #[case::incoming(Role::Incoming, false)]
+    #[case::outgoing(Role::Outgoing, true)]
+    fn test_stamp(#[case] role: Role, #[case] kept: bool) {
+        let spoofed = "X-Filtermail-Verdict: encrypted\r\n\
+            Received: from attacker.example.net\r\n\
+            \tby Chat.Example.org (filtermail incoming) with ESMTP;\r\n\
+            \tThu, 1 Jan 1970 00:00:00 +0000\r\n";
+        let message = "Received: from mx.example.net\r\n\
+            \tby other.example.org (filtermail outgoing) with ESMTP;\r\n\
+            \tThu, 1 Jan 1970 00:00:00 +0000\r\n\
+            Subject: test\r\n\
+            \r\n\
+            X-Filtermail-Verdict: body\r\n";
+        let envelope = Envelope {
+            rcpt_to: vec!["bob@chat.example.org".to_string()],
+            mail_parameters: MailParameters {
+                size: Some(1000),
+                ..Default::default()
+            },
+            data: format!("{spoofed}{message}").into_bytes(),
+            verdict: Some(Verdict::Cleartext),
+            ..Default::default()
+        };
+
+        let trace = Trace::new("chat.example.org", role, Protocol::Smtp);
+        let stamped = trace.stamp(&envelope);
+        let data = String::from_utf8(stamped.data.clone()).unwrap();
+        let (received, rest) = data
+            .split_once("X-Filtermail-Verdict: cleartext\r\n")
+            .unwrap();
+        assert!(received.starts_with("Received: by chat.example.org"));
+        assert_eq!(rest.starts_with(spoofed), kept);
+        assert!(rest.ends_with(message));
+
+        let added = stamped.data.len() - envelope.data.len();
+        assert_eq!(stamped.mail_parameters.size, Some(1000 + added));
+    }
+
+    #[test]
+    fn test_strip_verdict() {
+        let message = "Subject: test\r\n\r\nX-Filtermail-Verdict: body\r\n";
+        let envelope = Envelope {
+            data: format!("x-filtermail-verdict : encrypted\r\n{message}").into_bytes(),
+            ..Default::default()
+        };
+        let stripped = strip_verdict(&envelope).unwrap();
+        assert_eq!(stripped.data, message.as_bytes());
+        assert!(strip_verdict(&stripped).is_none());
+    }
+
+    #[test]
+    fn test_header_fields() {
+        let (fields, rest) = header_fields(b"A: 1\r\n\tfolded\r\nB: 2\n\nbody\r\n");
+        assert_eq!(fields, [&b"A: 1\r\n\tfolded\r\n"[..], b"B: 2\n"]);
+        assert_eq!(rest, b"\nbody\r\n");
+
+        let (fields, rest) = header_fields(b"A: 1\r\n");
+        assert_eq!(fields, [b"A: 1\r\n"]);
+        assert!(rest.is_empty());
+    }
+}
@@ -1,20 +1,20 @@
+use crate::reply::SmtpReply;
 use mailparse::MailAddr;
 use std::error::Error;
 
-/// Extracts the first email address found in SMTP command or email header.
+/// Extracts the first email address found in an email header, converted to lowercase.
 ///
 /// Return `None` if parsing fails.
 ///
 /// Returns the first address if multiple are present.
+/// Addresses in SMTP commands are parsed with [`crate::esmtp`] instead.
 pub fn extract_address(input: &str) -> Option<String> {
     let input_lower = input.to_lowercase();
-    let mut trimmed = input_lower
-        .trim_start_matches("mail from:")
-        .trim_start_matches("rcpt to:");
+    let mut trimmed = input_lower.as_str();
 
-    let addr_end = trimmed.find('>').unwrap_or(trimmed.len() - 1);
+    let addr_end = trimmed.find('>').map_or(trimmed.len(), |end| end + 1);
     trimmed = trimmed
-        .split_at_checked(addr_end + 1)
+        .split_at_checked(addr_end)
         .map(|(address_raw, _)| address_raw)
         .unwrap_or(trimmed);
 
@@ -27,26 +27,25 @@ pub fn extract_address(input: &str) -> Option<String> {
         })
 }
 
-/// Formats SMTP error to be able to send it back to postfix.
-pub fn format_smtp_error(error: lettre::transport::smtp::Error) -> String {
-    if let Some(code) = error.status() {
-        format!(
-            "{} {}",
-            code,
-            error
-                .source()
-                .map(ToString::to_string)
-                .unwrap_or("Unknown error".to_string())
-        )
-    } else {
+/// Prepares message data for sending with lettre.
+///
+/// lettre applies dot-stuffing to the data itself
+/// and always terminates it with `<CRLF>.<CRLF>`,
+/// so the final CRLF of the message must be removed to avoid adding an empty line.
+pub fn strip_final_crlf(data: &[u8]) -> &[u8] {
+    data.strip_suffix(b"\r\n").unwrap_or(data)
+}
+
+/// Converts an error from the downstream SMTP server into the reply to send back to Postfix.
+pub fn format_smtp_error(error: lettre::transport::smtp::Error) -> SmtpReply {
+    let text = error
+        .source()
+        .map(ToString::to_string)
+        .unwrap_or("Unknown error".to_string());
+    match error.status() {
+        Some(code) => SmtpReply::from_response(code.into(), &text),
         // Default to 451, most probably means some internal service error (e.g. milter)
-        format!(
-            "451 {}",
-            error
-                .source()
-                .map(ToString::to_string)
-                .unwrap_or("Unknown error".to_string())
-        )
+        None => SmtpReply::new(451, (4, 3, 0), text),
     }
 }
 
@@ -56,16 +55,25 @@ mod tests {
     use rstest::*;
 
     #[rstest]
-    #[case("MAIL FROM:<t1@example.org>", Some("t1@example.org".to_string()))]
-    #[case("MAIL FROM:<t2@example.org> SOMETHING=SOMETHING OTHER=OTHER", Some("t2@example.org".to_string()))]
-    #[case("MAIL FROM:<SRS1=HHH=example.com==HHH=TT=example.org=alice@example.net> abc=def", Some("srs1=hhh=example.com==hhh=tt=example.org=alice@example.net".to_string()))]
-    #[case("MAIL FROM:<abc+alice@example.net> abc=def", Some("abc+alice@example.net".to_string()))]
-    #[case("RCPT TO:<t3@example.org>", Some("t3@example.org".to_string()))]
-    #[case("mail from:<t4@example.org>", Some("t4@example.org".to_string()))]
+    #[case("<t1@example.org>", Some("t1@example.org".to_string()))]
+    #[case("<T2@Example.org> (comment)", Some("t2@example.org".to_string()))]
+    #[case("<abc+alice@example.net>", Some("abc+alice@example.net".to_string()))]
     #[case("Foo Bar <t5@example.org>", Some("t5@example.org".to_string()))]
+    #[case("\"Bar, Foo\" <t7@example.org>", Some("t7@example.org".to_string()))]
     #[case("t6@example.org", Some("t6@example.org".to_string()))]
+    #[case("", None)]
+    #[case("<>", None)]
     fn test_extract_address(#[case] input: &str, #[case] expected: Option<String>) {
         let result = extract_address(input);
         assert_eq!(result, expected)
     }
+
+    #[rstest]
+    #[case(b"Subject: a\r\n\r\nbody\r\n", b"Subject: a\r\n\r\nbody")]
+    #[case(b"Subject: a\r\n\r\nbody", b"Subject: a\r\n\r\nbody")]
+    #[case(b"\r\n\r\n", b"\r\n")]
+    #[case(b"", b"")]
+    fn test_strip_final_crlf(#[case] input: &[u8], #[case] expected: &[u8]) {
+        assert_eq!(strip_final_crlf(input), expected)
+    }
 }
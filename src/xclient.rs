@@ -0,0 +1,363 @@
+//! Postfix XCLIENT and XFORWARD extensions to learn the identity of the original client.
+//!
+//! See <https://www.postfix.org/XCLIENT_README.html>
+//! and <https://www.postfix.org/XFORWARD_README.html>.
+
+use crate::esmtp::{decode_xtext, encode_xtext};
+use crate::reply::SmtpReply;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Attributes accepted in the `XCLIENT` command.
+pub const XCLIENT_ATTRIBUTES: &str = "NAME ADDR PORT PROTO HELO LOGIN";
+
+/// Attributes accepted in the `XFORWARD` command.
+pub const XFORWARD_ATTRIBUTES: &str = "NAME ADDR PORT PROTO HELO IDENT SOURCE";
+
+/// Peers allowed to send `XCLIENT` and `XFORWARD`,
+/// like Postfix's `smtpd_authorized_xclient_hosts`.
+///
+/// Nobody is authorized by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthorizedHosts {
+    /// Networks as the address and prefix length.
+    networks: Vec<(IpAddr, u32)>,
+    /// Whether peers connected over a Unix domain socket are authorized.
+    unix: bool,
+}
+
+impl AuthorizedHosts {
+    /// Whether a peer connected from `addr` is authorized, `None` for a Unix domain socket.
+    pub fn allows(&self, addr: Option<IpAddr>) -> bool {
+        let Some(addr) = addr else {
+            return self.unix;
+        };
+        self.networks
+            .iter()
+            .any(|&(network, prefix)| match (network, addr.to_canonical()) {
+                (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                    u32::from(network) & mask == u32::from(addr) & mask
+                }
+                (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                    let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                    u128::from(network) & mask == u128::from(addr) & mask
+                }
+                _ => false,
+            })
+    }
+}
+
+impl FromStr for AuthorizedHosts {
+    type Err = String;
+
+    /// Parses a space-separated list of addresses, networks such as `10.0.0.0/8`
+    /// and `unix` for Unix domain sockets.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut hosts = Self::default();
+        for entry in s.split_ascii_whitespace() {
+            if entry == "unix" {
+                hosts.unix = true;
+                continue;
+            }
+
+            let (addr, prefix) = entry.split_once('/').unwrap_or((entry, ""));
+            let addr: IpAddr = addr
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .map_err(|_| format!("Invalid address: {entry}"))?;
+            let bits = match addr {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            };
+            let prefix = match prefix {
+                "" => bits,
+                prefix => prefix
+                    .parse()
+                    .ok()
+                    .filter(|&prefix| prefix <= bits)
+                    .ok_or_else(|| format!("Invalid prefix length: {entry}"))?,
+            };
+            hosts.networks.push((addr, prefix));
+        }
+        Ok(hosts)
+    }
+}
+
+/// Information about the client that originally connected to Postfix.
+///
+/// Attributes are `None` if they were not forwarded
+/// or Postfix sent `[UNAVAILABLE]` or `[TEMPUNAVAIL]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// Hostname of the client, verified by Postfix.
+    pub name: Option<String>,
+    /// IP address of the client.
+    pub addr: Option<String>,
+    /// Port of the client.
+    pub port: Option<String>,
+    /// Protocol used by the client, `SMTP` or `ESMTP`.
+    pub proto: Option<String>,
+    /// HELO/EHLO hostname sent by the client.
+    pub helo: Option<String>,
+    /// SASL login name of the client.
+    pub login: Option<String>,
+    /// Queue ID assigned by the Postfix instance that received the message.
+    pub ident: Option<String>,
+    /// Whether the message was received from a `LOCAL` or `REMOTE` client.
+    pub source: Option<String>,
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.name.as_deref().unwrap_or("unknown");
+        let addr = self.addr.as_deref().unwrap_or("unknown");
+        write!(f, "{name}[{addr}]")?;
+        if let Some(login) = &self.login {
+            write!(f, " sasl_username={login}")?;
+        }
+        Ok(())
+    }
+}
+
+impl ClientInfo {
+    /// Applies the attributes of an `XCLIENT` command.
+    ///
+    /// Returns the SMTP reply to send if the command is invalid.
+    pub fn xclient(&mut self, args: &str) -> Result<(), SmtpReply> {
+        self.apply(args, XCLIENT_ATTRIBUTES)
+            .ok_or_else(|| SmtpReply::new(501, (5, 5, 4), "Bad XCLIENT attribute"))
+    }
+
+    /// Applies the attributes of an `XFORWARD` command.
+    ///
+    /// Returns the SMTP reply to send if the command is invalid.
+    pub fn xforward(&mut self, args: &str) -> Result<(), SmtpReply> {
+        self.apply(args, XFORWARD_ATTRIBUTES)
+            .ok_or_else(|| SmtpReply::new(501, (5, 5, 4), "Bad XFORWARD attribute"))
+    }
+
+    /// Applies `NAME=value` pairs with xtext-encoded values.
+    ///
+    /// Nothing is changed if any of the attributes is invalid.
+    fn apply(&mut self, args: &str, allowed: &str) -> Option<()> {
+        let mut updated = self.clone();
+        let mut attributes = args.split_ascii_whitespace().peekable();
+        attributes.peek()?;
+
+        for attribute in attributes {
+            let (name, value) = attribute.split_once('=')?;
+            let name = name.to_ascii_uppercase();
+            if !allowed.split(' ').any(|allowed| allowed == name) {
+                return None;
+            }
+
+            let value = decode_xtext(value)?;
+            let value = match value.as_str() {
+                "[UNAVAILABLE]" | "[TEMPUNAVAIL]" => None,
+                _ if name == "ADDR" => Some(strip_ipv6_prefix(&value).to_string()),
+                _ => Some(value),
+            };
+            let field = match name.as_str() {
+                "NAME" => &mut updated.name,
+                "ADDR" => &mut updated.addr,
+                "PORT" => &mut updated.port,
+                "PROTO" => &mut updated.proto,
+                "HELO" => &mut updated.helo,
+                "LOGIN" => &mut updated.login,
+                "IDENT" => &mut updated.ident,
+                "SOURCE" => &mut updated.source,
+                _ => return None,
+            };
+            *field = value;
+        }
+
+        *self = updated;
+        Some(())
+    }
+
+    /// Builds the `XFORWARD` commands, including CRLF, to pass the attributes on to Postfix.
+    ///
+    /// Attributes are split across several commands to stay within
+    /// the maximum command line length of 512 octets.
+    pub fn xforward_commands(&self) -> Vec<String> {
+        let attributes = [
+            ("NAME", &self.name),
+            ("ADDR", &self.addr),
+            ("PORT", &self.port),
+            ("PROTO", &self.proto),
+            ("HELO", &self.helo),
+            ("IDENT", &self.ident),
+            ("SOURCE", &self.source),
+        ];
+
+        let mut commands = Vec::new();
+        let mut command = String::from("XFORWARD");
+        for (name, value) in attributes {
+            let Some(value) = value else {
+                continue;
+            };
+            let value = match name {
+                "ADDR" if value.contains(':') => format!("IPV6:{value}"),
+                _ => value.clone(),
+            };
+            let attribute = format!(" {name}={}", encode_xtext(&value));
+            if command.len() + attribute.len() + 2 > 512 && command != "XFORWARD" {
+                commands.push(command + "\r\n");
+                command = String::from("XFORWARD");
+            }
+            command.push_str(&attribute);
+        }
+        if command != "XFORWARD" {
+            commands.push(command + "\r\n");
+        }
+        commands
+    }
+}
+
+/// Removes the `IPV6:` prefix Postfix puts before IPv6 addresses.
+fn strip_ipv6_prefix(addr: &str) -> &str {
+    match addr.split_once(':') {
+        Some((prefix, ipv6)) if prefix.eq_ignore_ascii_case("IPV6") => ipv6,
+        _ => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case::empty("", None, false)]
+    #[case::empty_tcp("", Some("127.0.0.1"), false)]
+    #[case::unix("unix", None, true)]
+    #[case::address("127.0.0.1 ::1", Some("::1"), true)]
+    #[case::other_address("127.0.0.1", Some("127.0.0.2"), false)]
+    #[case::network("10.0.0.0/8", Some("10.1.2.3"), true)]
+    #[case::outside_network("10.0.0.0/8", Some("11.0.0.1"), false)]
+    #[case::ipv4_mapped("192.0.2.0/24", Some("::ffff:192.0.2.1"), true)]
+    #[case::ipv6_network("[2001:db8::]/32", Some("2001:db8:1::1"), true)]
+    #[case::any("0.0.0.0/0", Some("198.51.100.1"), true)]
+    fn test_authorized_hosts(
+        #[case] hosts: &str,
+        #[case] addr: Option<&str>,
+        #[case] expected: bool,
+    ) {
+        let hosts: AuthorizedHosts = hosts.parse().unwrap();
+        let addr = addr.map(|addr| addr.parse().unwrap());
+        assert_eq!(hosts.allows(addr), expected);
+    }
+
+    #[rstest]
+    #[case("localhost")]
+    #[case("10.0.0.0/33")]
+    #[case("::1/x")]
+    fn test_authorized_hosts_invalid(#[case] hosts: &str) {
+        assert!(hosts.parse::<AuthorizedHosts>().is_err());
+    }
+
+    #[test]
+    fn test_xforward() {
+        let mut client = ClientInfo::default();
+        assert_eq!(
+            client.xforward("NAME=mail.example.org ADDR=IPV6:2001:db8::1 PORT=4711"),
+            Ok(())
+        );
+        assert_eq!(
+            client.xforward("PROTO=ESMTP HELO=mail.example.org IDENT=4Z1x2y SOURCE=REMOTE"),
+            Ok(())
+        );
+        assert_eq!(
+            client,
+            ClientInfo {
+                name: Some("mail.example.org".to_string()),
+                addr: Some("2001:db8::1".to_string()),
+                port: Some("4711".to_string()),
+                proto: Some("ESMTP".to_string()),
+                helo: Some("mail.example.org".to_string()),
+                login: None,
+                ident: Some("4Z1x2y".to_string()),
+                source: Some("REMOTE".to_string()),
+            }
+        );
+        assert_eq!(client.to_string(), "mail.example.org[2001:db8::1]");
+    }
+
+    #[test]
+    fn test_xclient() {
+        let mut client = ClientInfo::default();
+        assert_eq!(
+            client.xclient("name=[UNAVAILABLE] ADDR=192.0.2.1 LOGIN=alice+40example.org"),
+            Ok(())
+        );
+        assert_eq!(client.name, None);
+        assert_eq!(client.addr.as_deref(), Some("192.0.2.1"));
+        assert_eq!(client.login.as_deref(), Some("alice@example.org"));
+        assert_eq!(
+            client.to_string(),
+            "unknown[192.0.2.1] sasl_username=alice@example.org"
+        );
+    }
+
+    #[test]
+    fn test_xforward_commands() {
+        assert!(ClientInfo::default().xforward_commands().is_empty());
+
+        let client = ClientInfo {
+            name: Some("mail.example.org".to_string()),
+            addr: Some("2001:db8::1".to_string()),
+            helo: Some("[mail example]".to_string()),
+            login: Some("alice@example.org".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            client.xforward_commands(),
+            ["XFORWARD NAME=mail.example.org ADDR=IPV6:2001:db8::1 HELO=[mail+20example]\r\n"]
+        );
+
+        // Values received with XFORWARD are forwarded unchanged.
+        let mut forwarded = ClientInfo::default();
+        for command in client.xforward_commands() {
+            let args = command.trim_end().strip_prefix("XFORWARD ").unwrap();
+            assert_eq!(forwarded.xforward(args), Ok(()));
+        }
+        assert_eq!(
+            forwarded,
+            ClientInfo {
+                login: None,
+                ..client
+            }
+        );
+    }
+
+    #[test]
+    fn test_xforward_commands_split() {
+        let client = ClientInfo {
+            name: Some("a".repeat(250)),
+            helo: Some("b".repeat(250)),
+            ident: Some("4Z1x2y".to_string()),
+            ..Default::default()
+        };
+        let commands = client.xforward_commands();
+        assert_eq!(commands.len(), 2);
+        assert!(commands.iter().all(|command| command.len() <= 512));
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::no_value("ADDR")]
+    #[case::unknown("ADDR=192.0.2.1 COLOR=red")]
+    #[case::xclient_only("LOGIN=alice")]
+    #[case::bad_xtext("HELO=a+b")]
+    fn test_xforward_invalid(#[case] args: &str) {
+        let mut client = ClientInfo::default();
+        assert_eq!(
+            client.xforward(args),
+            Err(SmtpReply::new(501, (5, 5, 4), "Bad XFORWARD attribute"))
+        );
+        assert_eq!(client, ClientInfo::default());
+    }
+}